@@ -0,0 +1,200 @@
+use crate::{
+	registry::Registry, router::Router, server_builder::Authenticate, CloseReason, Connection, HandshakeRequest,
+	Heartbeat, OriginPolicy,
+};
+use http_body_util::Full;
+use hyper::{
+	body::Bytes,
+	header::{HeaderValue, ORIGIN, UPGRADE},
+	Request, Response, StatusCode,
+};
+use hyper_tungstenite::{is_upgrade_request, upgrade};
+use serde::{de::DeserializeOwned, Serialize};
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+/// Turns upgrade requests into connections for their route, or the queue consumed by `SocketServer::accept_connection`
+pub(crate) struct Acceptor<M, E> {
+	pub sender: Sender<Connection<M, E>>,
+	pub router: Router<M, E>,
+	pub registry: Registry,
+	pub idle_timeout: Option<Duration>,
+	pub heartbeat: Option<Heartbeat>,
+	pub origin_policy: OriginPolicy,
+	pub authenticate: Option<Authenticate>,
+}
+
+impl<M, E> Clone for Acceptor<M, E> {
+	fn clone(&self) -> Self {
+		Acceptor {
+			sender: self.sender.clone(),
+			router: self.router.clone(),
+			registry: self.registry.clone(),
+			idle_timeout: self.idle_timeout,
+			heartbeat: self.heartbeat,
+			origin_policy: self.origin_policy.clone(),
+			authenticate: self.authenticate.clone(),
+		}
+	}
+}
+
+impl<M: Serialize + 'static, E: DeserializeOwned + 'static> Acceptor<M, E> {
+	pub async fn accept<B>(&self, mut request: Request<B>) -> Response<Full<Bytes>> {
+		if !is_upgrade_request(&request) {
+			let mut response = status_response(StatusCode::UPGRADE_REQUIRED, "Expected a WebSocket upgrade request");
+			response.headers_mut().insert(UPGRADE, HeaderValue::from_static("websocket"));
+
+			return response;
+		}
+
+		let mut handshake = HandshakeRequest::new(&request);
+
+		let handler = match self.router.find(&handshake.path) {
+			Some((handler, path_params)) => {
+				handshake.path_params = path_params;
+
+				Some(handler)
+			}
+			None if self.router.is_empty() => None,
+			None => return status_response(StatusCode::NOT_FOUND, "No route for this path"),
+		};
+
+		let (response, hyper_socket) = match upgrade(&mut request, None) {
+			Ok(upgrade) => upgrade,
+			Err(error) => return status_response(StatusCode::BAD_REQUEST, &format!("Invalid WebSocket upgrade: {error}")),
+		};
+
+		if let Some(origin) = request.headers().get(ORIGIN) {
+			if !origin.to_str().is_ok_and(|origin| self.origin_policy.allows(origin)) {
+				return status_response(StatusCode::FORBIDDEN, "Origin not allowed");
+			}
+		}
+
+		// The upgrade only happens once the response is sent, so a rejected client never gets a connection
+		let identity = match &self.authenticate {
+			Some(authenticate) => match authenticate(handshake.clone()).await {
+				Ok(identity) => Some(identity),
+				Err(status) => return status_response(status, status.canonical_reason().unwrap_or("Authentication failed")),
+			},
+			None => None,
+		};
+
+		let acceptor = self.clone();
+
+		tokio::spawn(async move {
+			let socket = match hyper_socket.await {
+				Ok(socket) => socket,
+				Err(error) => return log::debug!("WebSocket handshake failed: {error}"),
+			};
+
+			let connection = Connection::new(
+				socket,
+				handshake.into_details(identity),
+				acceptor.idle_timeout,
+				acceptor.heartbeat,
+				&acceptor.registry,
+			);
+
+			if let Some(handler) = handler {
+				return handler(connection).await;
+			}
+
+			if let Err(error) = acceptor.sender.send(connection).await {
+				error.0.reject(CloseReason::Away("Failed to queue connection".to_owned())).await;
+			}
+		});
+
+		response
+	}
+}
+
+pub(crate) fn status_response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+	let mut response = Response::new(Full::from(message.to_owned()));
+	*response.status_mut() = status;
+
+	response
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		tests::{connect_to, raw_request},
+		Event, SocketServer,
+	};
+	use hyper::header::{HeaderValue, COOKIE, ORIGIN};
+	use pretty_assertions::assert_eq;
+	use tokio_tungstenite::{connect_async, tungstenite::client::IntoClientRequest, MaybeTlsStream};
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpStream,
+	};
+
+	#[tokio::test]
+	async fn rejects_plain_http_requests() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let addr = server.local_addr().unwrap();
+
+		let response = raw_request(addr, "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+		assert!(response.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+		assert!(response.contains("upgrade: websocket\r\n"));
+
+		let response = raw_request(
+			addr,
+			"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\nUpgrade: websocket\r\n\r\n",
+		)
+		.await;
+		assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+		// A client which gives up halfway through a request doesn't take the server down either
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		stream.write_all(b"GET / HTTP/1.1\r\nHost: loc").await.unwrap();
+		drop(stream);
+
+		let (_connection, _client) = connect_to(&mut server, "/").await;
+	}
+
+	#[tokio::test]
+	async fn captures_request_details() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+
+		let mut request = format!("ws://{}/", server.local_addr().unwrap()).into_client_request().unwrap();
+		let headers = request.headers_mut();
+		headers.insert(COOKIE, HeaderValue::from_static("session=abc"));
+		headers.insert(ORIGIN, HeaderValue::from_static("https://example.com"));
+		headers.insert("x-request-id", HeaderValue::from_static("7"));
+
+		let (client, _) = connect_async(request).await.unwrap();
+		let MaybeTlsStream::Plain(stream) = client.get_ref() else {
+			panic!("Expected a plain TCP stream");
+		};
+		let client_addr = stream.local_addr().unwrap();
+
+		let mut connection = server.accept_connection().await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => {
+				assert_eq!(details.peer_addr, Some(client_addr));
+				assert_eq!(details.headers.get("x-request-id").unwrap(), "7");
+				assert_eq!(details.cookies.get("session").map(String::as_str), Some("abc"));
+				assert_eq!(details.origin.as_deref(), Some("https://example.com"));
+				assert_eq!(details.protocols, Vec::<String>::new());
+			}
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn keeps_plain_connections_alive() {
+		let server: SocketServer = SocketServer::new(0).await.unwrap();
+		let mut stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+
+		for _ in 0..2 {
+			stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+			let mut response = vec![0; 1024];
+			let length = stream.read(&mut response).await.unwrap();
+
+			assert_eq!(&response[..length].split(|byte| *byte == b'\r').next().unwrap(), b"HTTP/1.1 426 Upgrade Required");
+		}
+	}
+}
@@ -0,0 +1,75 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use serde::Deserialize;
+use serde_json::{from_value, json};
+use socket_server::SocketMessage;
+
+#[derive(Deserialize)]
+struct OwnedEvent {
+	kind: String,
+	items: Vec<OwnedItem>,
+}
+
+#[derive(Deserialize)]
+struct OwnedItem {
+	id: u64,
+	name: String,
+}
+
+#[derive(Deserialize)]
+struct BorrowedEvent<'a> {
+	kind: &'a str,
+	#[serde(borrow)]
+	items: Vec<BorrowedItem<'a>>,
+}
+
+#[derive(Deserialize)]
+struct BorrowedItem<'a> {
+	id: u64,
+	name: &'a str,
+}
+
+fn large_event() -> SocketMessage {
+	let items: Vec<_> = (0..1000).map(|id| json!({ "id": id, "name": format!("item {id}") })).collect();
+	let body = json!({ "kind": "import", "items": items });
+
+	SocketMessage::parse(format!("event() {body}")).unwrap()
+}
+
+fn deserialize_body(c: &mut Criterion) {
+	let message = large_event();
+	let mut group = c.benchmark_group("deserialize_body");
+
+	group.bench_function("get_body and from_value", |b| {
+		b.iter(|| {
+			let body = black_box(&message).get_body().unwrap().unwrap();
+			let event: OwnedEvent = from_value(body).unwrap();
+
+			(event.kind.len(), event.items.iter().map(|item| item.id as usize + item.name.len()).sum::<usize>())
+		})
+	});
+
+	group.bench_function("body_as owned", |b| {
+		b.iter(|| {
+			let event = black_box(&message).body_as::<OwnedEvent>().unwrap().unwrap();
+
+			(event.kind.len(), event.items.iter().map(|item| item.id as usize + item.name.len()).sum::<usize>())
+		})
+	});
+
+	group.bench_function("body_as borrowed", |b| {
+		b.iter(|| {
+			let event = black_box(&message).body_as::<BorrowedEvent>().unwrap().unwrap();
+
+			(event.kind.len(), event.items.iter().map(|item| item.id as usize + item.name.len()).sum::<usize>())
+		})
+	});
+
+	group.bench_function("get_body_raw", |b| {
+		b.iter(|| black_box(&message).get_body_raw().unwrap().unwrap().get().len())
+	});
+
+	group.finish();
+}
+
+criterion_group!(benches, deserialize_body);
+criterion_main!(benches);
@@ -0,0 +1,548 @@
+use crate::{SocketMessage, SocketMessageBuilder};
+use error_stack::ResultExt;
+use futures::{SinkExt, StreamExt};
+use json_patch::Patch;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+	sync::{Arc, Mutex},
+	time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+	select,
+	sync::{
+		broadcast::{self, error::RecvError},
+		mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+	},
+	time::sleep,
+};
+use tokio_tungstenite::connect_async;
+use tungstenite::Message;
+use url::Url;
+use uuid::Uuid;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MODEL_BUFFER: usize = 64;
+
+#[derive(Debug, Error)]
+pub enum SocketClientError {
+	#[error("The client's connection task has stopped")]
+	Stopped,
+
+	#[error("Failed to serialize the event")]
+	Serialize,
+}
+
+type Result<T> = error_stack::Result<T, SocketClientError>;
+
+pub type ErrorHandler = Arc<dyn Fn(String) + Send + Sync>;
+
+#[derive(Clone)]
+struct Model {
+	pin: Option<String>,
+	body: Value,
+}
+
+#[derive(Clone, Default)]
+struct Errors {
+	handler: Arc<Mutex<Option<ErrorHandler>>>,
+}
+
+impl Errors {
+	fn set(&self, handler: ErrorHandler) {
+		self.handler.lock().unwrap().replace(handler);
+	}
+
+	fn report(&self, message: String) {
+		// The handler is called without holding the lock, so that it can report errors or replace itself
+		let handler = self.handler.lock().unwrap().clone();
+
+		match handler {
+			Some(handler) => handler(message),
+			None => log::error!("{message}"),
+		}
+	}
+}
+
+/// A client for `SocketServer`, mirroring the `SocketClient` in client.ts. The connection is managed by a
+/// background task, which reconnects and sends `sync` when the server closes the socket without a reason
+pub struct SocketClient {
+	outgoing: UnboundedSender<String>,
+	models: broadcast::Sender<Model>,
+	errors: Errors,
+}
+
+impl SocketClient {
+	pub fn new(address: Url) -> SocketClient {
+		let (outgoing, outgoing_receiver) = unbounded_channel();
+		let (models, _) = broadcast::channel(MODEL_BUFFER);
+		let errors = Errors::default();
+
+		tokio::spawn(run(address, outgoing_receiver, models.clone(), errors.clone()));
+
+		SocketClient {
+			outgoing,
+			models,
+			errors,
+		}
+	}
+
+	/// Send an event. Resolves when a new model has been received which contains the computed result of this event
+	pub async fn send<T: Serialize>(&self, event: &T) -> Result<()> {
+		// Subscribe before sending so that the responding model can't be missed
+		let mut models = self.models.subscribe();
+		let pin = self.internal_ping(event)?;
+
+		loop {
+			match models.recv().await {
+				Ok(model) if model.pin.as_deref() == Some(pin.as_str()) => return Ok(()),
+				Ok(_) | Err(RecvError::Lagged(_)) => continue,
+				Err(RecvError::Closed) => Err(SocketClientError::Stopped)?,
+			}
+		}
+	}
+
+	/// Send an event. Resolves as soon as the event is queued, without waiting for an updated model to be sent back
+	pub fn ping<T: Serialize>(&self, event: &T) -> Result<()> {
+		self.internal_ping(event)?;
+
+		Ok(())
+	}
+
+	/// Subscribe to model updates. Dropping the subscription unsubscribes
+	pub fn subscribe(&self) -> Subscription {
+		Subscription {
+			models: self.models.subscribe(),
+		}
+	}
+
+	/// Handle all errors from the socket. The socket will normally try to reconnect, but sometimes will still receive an
+	/// internal error. Errors are logged with `log::error!` when no handler is set
+	pub fn set_error_handler<F: Fn(String) + Send + Sync + 'static>(&self, handler: F) {
+		self.errors.set(Arc::new(handler));
+	}
+
+	fn internal_ping<T: Serialize>(&self, event: &T) -> Result<String> {
+		let pin = Uuid::new_v4().to_string();
+		let message = SocketMessageBuilder::new("event")
+			.context(pin.clone())
+			.body(event)
+			.build_string()
+			.change_context(SocketClientError::Serialize)?;
+
+		self.outgoing.send(message).map_err(|_| SocketClientError::Stopped)?;
+
+		Ok(pin)
+	}
+}
+
+pub struct Subscription {
+	models: broadcast::Receiver<Model>,
+}
+
+impl Subscription {
+	/// Wait for the next model. Models are skipped if the subscriber falls too far behind. Returns `None` once the
+	/// client has been dropped
+	pub async fn next(&mut self) -> Option<Value> {
+		loop {
+			match self.models.recv().await {
+				Ok(model) => return Some(model.body),
+				Err(RecvError::Lagged(_)) => continue,
+				Err(RecvError::Closed) => return None,
+			}
+		}
+	}
+}
+
+async fn run(
+	address: Url,
+	mut outgoing: UnboundedReceiver<String>,
+	models: broadcast::Sender<Model>,
+	errors: Errors,
+) {
+	let mut pending: Option<String> = None;
+	let mut current = CurrentModel::default();
+	let mut is_reconnect = false;
+
+	loop {
+		let mut socket = match connect_async(address.as_str()).await {
+			Ok((socket, _)) => socket,
+			Err(error) => {
+				if outgoing.is_closed() {
+					return;
+				}
+
+				errors.report(format!("Failed to connect: {error}"));
+				sleep(RECONNECT_DELAY).await;
+
+				continue;
+			}
+		};
+
+		// A sync sent over the previous socket won't be answered
+		current.syncing = false;
+
+		if is_reconnect && socket.send(Message::Text(current.sync_message())).await.is_err() {
+			continue;
+		}
+
+		if let Some(text) = pending.take() {
+			if socket.send(Message::Text(text.clone())).await.is_err() {
+				pending = Some(text);
+
+				continue;
+			}
+		}
+
+		let mut closed_with_reason = false;
+
+		while pending.is_none() {
+			select! {
+				text = outgoing.recv() => match text {
+					Some(text) => {
+						if socket.send(Message::Text(text.clone())).await.is_err() {
+							pending = Some(text);
+						}
+					}
+					None => {
+						let _ = socket.close(None).await;
+
+						return;
+					}
+				},
+				message = socket.next() => match message {
+					Some(Ok(Message::Text(text))) => {
+						if !handle_message(text, &mut current, &models, &errors) && !current.syncing {
+							let _ = socket.send(Message::Text(current.sync_message())).await;
+						}
+					}
+					Some(Ok(Message::Close(frame))) => {
+						if let Some(frame) = frame.filter(|frame| !frame.reason.is_empty()) {
+							errors.report(format!("Socket failed: {}", frame.reason));
+							closed_with_reason = true;
+						}
+
+						break;
+					}
+					Some(Ok(_)) => (),
+					Some(Err(_)) | None => break,
+				},
+			}
+		}
+
+		is_reconnect = true;
+
+		// Like the TS client, a socket that was closed for a reason is only reopened once there is something to send
+		if closed_with_reason && pending.is_none() {
+			match outgoing.recv().await {
+				Some(text) => pending = Some(text),
+				None => return,
+			}
+		}
+	}
+}
+
+/// The latest model received from the server, used to apply patches
+#[derive(Default)]
+struct CurrentModel {
+	value: Option<Value>,
+	version: u64,
+	/// Set once `sync` has been sent, until an update arrives which the client can apply. Every update missed meanwhile
+	/// is answered by the same sync, so no more are sent
+	syncing: bool,
+}
+
+impl CurrentModel {
+	fn sync_message(&mut self) -> String {
+		self.syncing = true;
+
+		match self.value {
+			Some(_) => format!("sync({})", self.version),
+			None => "sync".to_owned(),
+		}
+	}
+}
+
+/// Returns false if updates have been missed and `sync` should be sent to catch up
+fn handle_message(text: String, current: &mut CurrentModel, models: &broadcast::Sender<Model>, errors: &Errors) -> bool {
+	let message = match SocketMessage::parse(text) {
+		Ok(message) => message,
+		Err(error) => {
+			errors.report(format!("Failed to parse message from server: {error}"));
+
+			return true;
+		}
+	};
+
+	let prefix = message.get_prefix();
+
+	if prefix != "model" && prefix != "patch" {
+		return true;
+	}
+
+	// The context is the model version, followed by the pin of the event that the model responds to, if any
+	let context = message.get_context().unwrap_or("");
+	let (version, pin) = context.split_once(':').unwrap_or((context, ""));
+
+	let version = match version.parse::<u64>() {
+		Ok(version) => version,
+		Err(_) => {
+			errors.report(format!("Expected to receive a version from server along with {prefix}"));
+
+			return true;
+		}
+	};
+
+	let body = if prefix == "model" {
+		match message.get_body() {
+			Ok(body) => body.unwrap_or(Value::Null),
+			Err(error) => {
+				errors.report(format!("Received a model from server with an invalid body: {}", error.current_context()));
+				current.value.take();
+
+				return false;
+			}
+		}
+	} else {
+		if current.value.is_some() && version <= current.version {
+			return true;
+		}
+
+		if current.value.is_none() || version != current.version + 1 {
+			return false;
+		}
+
+		let patch = match message.body_as::<Patch>() {
+			Ok(Some(patch)) => patch,
+			_ => {
+				errors.report("Expected to receive a JSON patch body from server along with patch".to_owned());
+				current.value.take();
+
+				return false;
+			}
+		};
+
+		let mut body = current.value.take().unwrap_or(Value::Null);
+
+		if let Err(error) = json_patch::patch(&mut body, &patch) {
+			errors.report(format!("Failed to apply patch from server: {error}"));
+
+			return false;
+		}
+
+		body
+	};
+
+	current.value.replace(body.clone());
+	current.version = version;
+	current.syncing = false;
+
+	let _ = models.send(Model {
+		pin: Some(pin).filter(|pin| !pin.is_empty()).map(ToOwned::to_owned),
+		body,
+	});
+
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Event, SocketServer};
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+
+	#[tokio::test]
+	async fn send_resolves_with_the_responding_model() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let mut subscription = client.subscribe();
+
+		tokio::spawn(async move {
+			let mut connection = server.accept_connection().await.unwrap();
+
+			while let Some(event) = connection.next_event().await {
+				if let Event::Update(body) = event {
+					connection.send(&json!({ "received": body })).await.unwrap();
+				}
+			}
+		});
+
+		client.send(&json!(1)).await.unwrap();
+		assert_eq!(subscription.next().await, Some(json!({ "received": 1 })));
+
+		client.ping(&json!(2)).unwrap();
+		assert_eq!(subscription.next().await, Some(json!({ "received": 2 })));
+	}
+
+	#[tokio::test]
+	async fn applies_patches_to_the_current_model() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let mut subscription = client.subscribe();
+
+		let mut connection = server.accept_connection().await.unwrap();
+		connection.next_event().await.unwrap();
+
+		let mut document = json!({ "title": "Notes", "items": (0..20).map(|n| format!("item {n}")).collect::<Vec<_>>() });
+		connection.send(&document).await.unwrap();
+		assert_eq!(subscription.next().await.as_ref(), Some(&document));
+
+		document["items"][3] = json!("changed");
+		connection.send(&document).await.unwrap();
+		assert_eq!(subscription.next().await.as_ref(), Some(&document));
+	}
+
+	#[tokio::test]
+	async fn syncs_from_the_last_version_when_a_patch_is_missed() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let mut subscription = client.subscribe();
+
+		let mut connection = server.accept_connection().await.unwrap();
+
+		for text in ["model(1) [1]", "patch(3) [{\"op\":\"add\",\"path\":\"/-\",\"value\":3}]"] {
+			connection.writer.send_text(text.to_owned()).await.unwrap();
+		}
+
+		assert_eq!(subscription.next().await, Some(json!([1])));
+
+		match connection.reader.stream.next().await {
+			Some(Ok(Message::Text(text))) => assert_eq!(text, "sync(1)"),
+			message => panic!("Expected a sync message, got {message:?}"),
+		}
+
+		for text in ["patch(2) [{\"op\":\"add\",\"path\":\"/-\",\"value\":2}]", "patch(3) [{\"op\":\"add\",\"path\":\"/-\",\"value\":3}]"] {
+			connection.writer.send_text(text.to_owned()).await.unwrap();
+		}
+
+		assert_eq!(subscription.next().await, Some(json!([1, 2])));
+		assert_eq!(subscription.next().await, Some(json!([1, 2, 3])));
+	}
+
+	#[tokio::test]
+	async fn sends_one_sync_for_every_patch_missed_before_it_is_answered() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let mut subscription = client.subscribe();
+
+		let mut connection = server.accept_connection().await.unwrap();
+
+		for version in 3..6 {
+			let patch = format!("patch({version}) [{{\"op\":\"add\",\"path\":\"/-\",\"value\":{version}}}]");
+			connection.writer.send_text(patch).await.unwrap();
+		}
+
+		connection.writer.send_text("model(5) [3,4,5]".to_owned()).await.unwrap();
+		assert_eq!(subscription.next().await, Some(json!([3, 4, 5])));
+
+		// The event marks the end of what the client sent for the patches
+		client.ping(&json!("after sync")).unwrap();
+
+		let mut sent = Vec::new();
+
+		while let Some(Ok(Message::Text(text))) = connection.reader.stream.next().await {
+			let prefix = SocketMessage::parse(text.as_str()).unwrap().get_prefix().to_owned();
+			sent.push(text);
+
+			if prefix == "event" {
+				break;
+			}
+		}
+
+		assert_eq!(sent.len(), 2, "Expected one sync before the event, got {sent:?}");
+		assert_eq!(sent[0], "sync");
+	}
+
+	#[tokio::test]
+	async fn resyncs_after_a_model_with_an_invalid_body() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let (sender, errors) = std::sync::mpsc::channel();
+		client.set_error_handler(move |error| sender.send(error).unwrap());
+		let mut subscription = client.subscribe();
+
+		let mut connection = server.accept_connection().await.unwrap();
+
+		connection.writer.send_text("model(1) [1]".to_owned()).await.unwrap();
+		assert_eq!(subscription.next().await, Some(json!([1])));
+
+		connection.writer.send_text("model(2) [1,".to_owned()).await.unwrap();
+
+		// The stale model is dropped, so the sync asks for a whole model
+		match connection.reader.stream.next().await {
+			Some(Ok(Message::Text(text))) => assert_eq!(text, "sync"),
+			message => panic!("Expected a sync message, got {message:?}"),
+		}
+
+		assert!(errors.try_recv().unwrap().starts_with("Received a model from server with an invalid body"));
+	}
+
+	#[tokio::test]
+	async fn reconnects_and_syncs_after_a_plain_close() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+
+		let mut first = server.accept_connection().await.unwrap();
+		first.close("").await;
+
+		let mut second = server.accept_connection().await.unwrap();
+
+		match second.reader.stream.next().await {
+			Some(Ok(Message::Text(text))) => assert_eq!(text, "sync"),
+			message => panic!("Expected a sync message, got {message:?}"),
+		}
+
+		client.ping(&json!("after reconnect")).unwrap();
+		second.next_event().await.unwrap();
+
+		match second.next_event().await {
+			Some(Event::Update(body)) => assert_eq!(body, json!("after reconnect")),
+			event => panic!("Expected an update event, got {event:?}"),
+		}
+	}
+
+	#[test]
+	fn error_handlers_can_replace_themselves() {
+		let errors = Errors::default();
+		let (sender, receiver) = std::sync::mpsc::channel();
+		let inner = errors.clone();
+
+		errors.set(Arc::new(move |message| {
+			let sender = sender.clone();
+
+			inner.set(Arc::new(move |message| sender.send(message).unwrap()));
+			inner.report(format!("Replaced after {message}"));
+		}));
+
+		errors.report("the first error".to_owned());
+		assert_eq!(receiver.recv().unwrap(), "Replaced after the first error");
+	}
+
+	#[tokio::test]
+	async fn reports_close_reasons_and_reconnects_lazily() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let client = SocketClient::new(Url::parse(&format!("ws://{}", server.local_addr().unwrap())).unwrap());
+		let (errors, mut errors_receiver) = unbounded_channel();
+
+		client.set_error_handler(move |message| {
+			let _ = errors.send(message);
+		});
+
+		let mut first = server.accept_connection().await.unwrap();
+		first.close("Going away").await;
+
+		assert_eq!(errors_receiver.recv().await.unwrap(), "Socket failed: Going away");
+
+		client.ping(&json!(null)).unwrap();
+
+		let mut second = server.accept_connection().await.unwrap();
+		second.next_event().await.unwrap();
+
+		match second.next_event().await {
+			Some(Event::Update(body)) => assert_eq!(body, Value::Null),
+			event => panic!("Expected an update event, got {event:?}"),
+		}
+	}
+}
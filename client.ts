@@ -1,3 +1,4 @@
+import { applyPatch, PatchOperation } from './json_patch.ts'
 import { parseSocketMessage, stringifySocketMessage } from './socket_message.ts'
 
 export type ErrorHandler = (message: string) => void
@@ -7,6 +8,7 @@ export class SocketClient {
 	#socketPromise: Promise<WebSocket>
 	#fatalErrorHandler: ErrorHandler | null = null
 	#modelListeners = new Set<(pin: string, model: unknown) => void>()
+	#model: { value: unknown; version: number } | null = null
 
 	constructor(address: URL) {
 		this.#address = address
@@ -68,18 +70,49 @@ export class SocketClient {
 		console.error(message)
 	}
 
-	#handleMessage(data: string) {
+	#handleMessage(socket: WebSocket, data: string) {
 		const message = parseSocketMessage(data)
-		if (message.prefix !== 'model') return
+		if (message.prefix !== 'model' && message.prefix !== 'patch') return
 
-		// If there is no message context, we want to log the error and continue, because missing one message isn't the end of the world
-		if (!message.context) return this.#handleError('Expected to receive a pin from server along with model')
+		// The context is the model version, followed by the pin of the event that the model responds to, if any
+		const [versionString, pin = ''] = (message.context ?? '').split(':')
+		const version = Number(versionString)
+
+		// If there is no version, we want to log the error and continue, because missing one message isn't the end of the world
+		if (!versionString || !Number.isInteger(version)) {
+			return this.#handleError(`Expected to receive a version from server along with ${message.prefix}`)
+		}
+
+		let model: unknown
+
+		if (message.prefix === 'model') model = message.body
+		else {
+			if (this.#model && version <= this.#model.version) return
+
+			// A patch can only be applied to the version right before it, so catch up if anything was missed
+			if (!this.#model || version !== this.#model.version + 1) return this.#requestSync(socket)
+
+			try {
+				model = applyPatch(this.#model.value, message.body as PatchOperation[])
+			} catch (error) {
+				this.#model = null
+				this.#handleError(`Failed to apply patch from server: ${error}`)
+
+				return this.#requestSync(socket)
+			}
+		}
+
+		this.#model = { value: model, version }
 
 		for (const fn of this.#modelListeners) {
-			fn(message.context, message.body)
+			fn(pin, model)
 		}
 	}
 
+	#requestSync(socket: WebSocket) {
+		socket.send(this.#model ? `sync(${this.#model.version})` : 'sync')
+	}
+
 	#socketOwnerId: string | null = null
 	async #connect() {
 		const socket = new WebSocket(this.#address)
@@ -97,7 +130,7 @@ export class SocketClient {
 		socket.onmessage = ({ data }) => {
 			if (typeof data !== 'string') return
 
-			this.#handleMessage(data)
+			this.#handleMessage(socket, data)
 		}
 
 		await new Promise<void>((resolve) => {
@@ -0,0 +1,103 @@
+use std::borrow::Cow;
+use tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+
+/// Why a connection was closed, which decides the close code the client receives. Clients can use the code to decide
+/// whether reconnecting would help: 1002 and 1003 mean the client is sending something the server doesn't understand,
+/// 1008 that it broke a rule of the server, and 1011 that the server failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+	/// 1000: The connection did what it was for
+	Normal(String),
+	/// 1001: The server is going away, such as on shutdown
+	Away(String),
+	/// 1002: The client sent a message which doesn't follow the protocol
+	Protocol(String),
+	/// 1003: The client sent a kind of data which the server doesn't accept, such as a binary message or an event of the
+	/// wrong type
+	Unsupported(String),
+	/// 1008: The client broke a policy of the server, such as by staying idle or not answering pings
+	Policy(String),
+	/// 1011: The server failed to handle the connection
+	Internal(String),
+	/// A code of the application's own, which must be in the 4000-4999 range. Other codes are sent as 1011
+	Application(u16, String),
+}
+
+impl CloseReason {
+	pub fn code(&self) -> CloseCode {
+		match self {
+			CloseReason::Normal(_) => CloseCode::Normal,
+			CloseReason::Away(_) => CloseCode::Away,
+			CloseReason::Protocol(_) => CloseCode::Protocol,
+			CloseReason::Unsupported(_) => CloseCode::Unsupported,
+			CloseReason::Policy(_) => CloseCode::Policy,
+			CloseReason::Internal(_) => CloseCode::Error,
+			CloseReason::Application(code @ 4000..=4999, _) => CloseCode::Library(*code),
+			CloseReason::Application(..) => CloseCode::Error,
+		}
+	}
+
+	pub fn message(&self) -> &str {
+		match self {
+			CloseReason::Normal(message)
+			| CloseReason::Away(message)
+			| CloseReason::Protocol(message)
+			| CloseReason::Unsupported(message)
+			| CloseReason::Policy(message)
+			| CloseReason::Internal(message)
+			| CloseReason::Application(_, message) => message,
+		}
+	}
+
+	pub(crate) fn into_frame(self) -> CloseFrame<'static> {
+		CloseFrame {
+			code: self.code(),
+			reason: Cow::Owned(truncate_close_reason(self.message().to_owned())),
+		}
+	}
+}
+
+/// Which side of a connection sent the first close frame, see `Event::Disconnected`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Initiator {
+	Client,
+	Server,
+}
+
+// Close frame payloads are limited to 125 bytes, two of which are taken by the close code
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+fn truncate_close_reason(mut reason: String) -> String {
+	if reason.len() > MAX_CLOSE_REASON_LEN {
+		let mut end = MAX_CLOSE_REASON_LEN;
+
+		while !reason.is_char_boundary(end) {
+			end -= 1;
+		}
+
+		reason.truncate(end);
+	}
+
+	reason
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn keeps_application_codes_in_their_range() {
+		assert_eq!(CloseReason::Application(4000, "Kicked".to_owned()).code(), CloseCode::Library(4000));
+		assert_eq!(CloseReason::Application(4999, "Kicked".to_owned()).code(), CloseCode::Library(4999));
+		assert_eq!(CloseReason::Application(1000, "Kicked".to_owned()).code(), CloseCode::Error);
+		assert_eq!(CloseReason::Application(5000, "Kicked".to_owned()).code(), CloseCode::Error);
+	}
+
+	#[test]
+	fn truncates_long_reasons_on_char_boundaries() {
+		let frame = CloseReason::Normal("é".repeat(100)).into_frame();
+
+		assert_eq!(frame.reason, "é".repeat(61));
+	}
+}
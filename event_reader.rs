@@ -0,0 +1,210 @@
+use crate::{
+	reader::{Reader, Step},
+	CloseReason, Connection, Event, Heartbeat, ModelSender, Signal,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{pin::pin, time::Duration};
+use tokio::{
+	select,
+	sync::{mpsc::UnboundedSender, oneshot},
+};
+
+/// The receiving half of a split connection, see `Connection::split`
+pub struct EventReader<E = Value> {
+	reader: Reader<E>,
+	signals: UnboundedSender<Signal>,
+	/// Dropped along with the reader, which stops the writer task
+	_alive: oneshot::Sender<()>,
+}
+
+impl<M: Serialize, E: DeserializeOwned> Connection<M, E> {
+	/// Split the connection so that models can be pushed from other tasks while waiting for events. Everything the
+	/// client is sent is written by a task of its own, which runs until the connection is closed or the `EventReader` is
+	/// dropped. The tick interval and idle timeout carry over to the `EventReader`
+	pub fn split(self) -> (EventReader<E>, ModelSender<M>) {
+		let sender = self.handle();
+		let (alive, dropped) = oneshot::channel::<()>();
+		let mut writer = self.writer;
+		let mut signals = self.signals;
+		let registration = self.registration;
+
+		tokio::spawn(async move {
+			let mut dropped = pin!(dropped);
+
+			loop {
+				// Signals go first, so everything sent before the reader was dropped is still written
+				let signal = select! {
+					biased;
+					Some(signal) = signals.recv() => signal,
+					_ = dropped.as_mut() => break,
+				};
+
+				if !writer.handle_signal(signal).await {
+					break;
+				}
+			}
+
+			drop(registration);
+		});
+
+		let reader = EventReader {
+			reader: self.reader,
+			signals: self.signals_sender,
+			_alive: alive,
+		};
+
+		(reader, sender)
+	}
+}
+
+impl<E: DeserializeOwned> EventReader<E> {
+	/// Make `next_event` yield `Event::Tick` every `period`. Pass `None` to stop ticking
+	pub fn set_tick_interval(&mut self, period: Option<Duration>) {
+		self.reader.set_tick_interval(period);
+	}
+
+	/// Make `next_event` close the connection when the client sends nothing for `timeout`. Pass `None` to never close
+	/// idle connections
+	pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+		self.reader.set_idle_timeout(timeout);
+	}
+
+	/// See `Connection::set_heartbeat`
+	pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+		self.reader.set_heartbeat(heartbeat);
+	}
+
+	/// See `Connection::latency`
+	pub fn latency(&self) -> Option<Duration> {
+		self.reader.latency()
+	}
+
+	/// Get the next event from the client, or a tick. However the connection ends, including when it's closed through
+	/// the `ModelSender`, `Event::Disconnected` says why, and `None` is returned after it
+	pub async fn next_event(&mut self) -> Option<Event<E>> {
+		loop {
+			match self.reader.next(None).await {
+				Step::Event(event) => return event,
+				Step::Update(pin, event) => {
+					let _ = self.signals.send(Signal::Pin(pin));
+
+					return Some(Event::Update(event));
+				}
+				Step::Signal(signal) => {
+					let _ = self.signals.send(signal);
+				}
+				Step::Close(reason, _) => {
+					self.close_with(reason.clone());
+
+					return Some(self.reader.end(&reason));
+				}
+			}
+		}
+	}
+
+	/// Close the connection normally with a reason, after the models which were already sent
+	pub fn close<S: Into<String>>(&self, reason: S) {
+		self.close_with(CloseReason::Normal(reason.into()));
+	}
+
+	/// Close the connection with the close code of `reason`, after the models which were already sent
+	pub fn close_with(&self, reason: CloseReason) {
+		let _ = self.signals.send(Signal::Close(reason));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		tests::{connect_to, disconnection, next_close_reason, next_text},
+		CloseCode, Event, Initiator, SocketServer,
+	};
+	use futures::{SinkExt, StreamExt};
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+	use std::time::Duration;
+	use tokio::time::timeout;
+	use tungstenite::Message;
+	use uuid::Uuid;
+
+	#[tokio::test]
+	async fn pushes_models_while_waiting_for_events() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, mut client) = connect_to(&mut server, "/").await;
+		let (mut reader, sender) = connection.split();
+
+		assert!(matches!(reader.next_event().await, Some(Event::Connect(_))));
+
+		let waiting = tokio::spawn(async move {
+			let event = reader.next_event().await;
+
+			(reader, event)
+		});
+
+		sender.send(&json!([1])).unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) [1]");
+
+		let pin = Uuid::from_u128(7);
+		client.send(Message::Text(format!("event({pin}) 2"))).await.unwrap();
+
+		let (mut reader, event) = waiting.await.unwrap();
+		assert!(matches!(event, Some(Event::Update(value)) if value == json!(2)));
+
+		sender.send(&json!([1, 2])).unwrap();
+		assert_eq!(next_text(&mut client).await, format!("model(2:{pin}) [1,2]"));
+
+		// Sync requests are read by the reader, and answered by the writer
+		let waiting = tokio::spawn(async move { reader.next_event().await });
+
+		client.send(Message::Text("sync".to_owned())).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(2) [1,2]");
+
+		assert!(sender.close("Done"));
+		assert_eq!(next_close_reason(&mut client).await, "Done");
+
+		// Polling the client again flushes its reply to the close frame
+		assert!(client.next().await.is_none());
+		assert_eq!(disconnection(waiting.await.unwrap()), (CloseCode::Normal, Initiator::Server));
+	}
+
+	#[tokio::test]
+	async fn disconnects_when_the_client_never_answers_the_close() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, _client) = connect_to(&mut server, "/").await;
+		let (mut reader, sender) = connection.split();
+
+		assert!(matches!(reader.next_event().await, Some(Event::Connect(_))));
+		assert!(sender.close("Done"));
+
+		// The client is never polled, so it never replies to the close frame
+		let event = timeout(Duration::from_secs(1), reader.next_event()).await.unwrap();
+
+		assert_eq!(disconnection(event), (CloseCode::Normal, Initiator::Server));
+		assert!(reader.next_event().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn closes_after_queued_models() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, mut client) = connect_to(&mut server, "/").await;
+		let (reader, sender) = connection.split();
+
+		sender.send(&json!([1])).unwrap();
+		reader.close("Done");
+		drop(reader);
+
+		assert_eq!(next_text(&mut client).await, "model(1) [1]");
+		assert_eq!(next_close_reason(&mut client).await, "Done");
+	}
+
+	#[tokio::test]
+	async fn closes_split_connections_on_shutdown() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, mut client) = connect_to(&mut server, "/").await;
+		let (_reader, _sender) = connection.split();
+
+		assert!(server.shutdown("Restarting", Duration::from_secs(1)).await);
+		assert_eq!(next_close_reason(&mut client).await, "Restarting");
+	}
+}
@@ -0,0 +1,130 @@
+use crate::ConnectionDetails;
+use hyper::{
+	header::{HeaderMap, COOKIE, ORIGIN, SEC_WEBSOCKET_PROTOCOL},
+	Request,
+};
+use std::{any::Any, collections::HashMap, net::SocketAddr, sync::Arc};
+
+/// The parts of a WebSocket upgrade request which are known before the upgrade is answered
+#[derive(Debug, Clone)]
+pub struct HandshakeRequest {
+	pub path: String,
+	pub query_params: HashMap<String, String>,
+	/// The `:param` segments captured by the route of the path, if routes were added with `SocketServerBuilder::route`
+	pub path_params: HashMap<String, String>,
+	/// The address of the client. Read from a `SocketAddr` request extension, which `SocketServer` always inserts. When
+	/// mounting `SocketServer::service` in another server, insert one with middleware to fill this in
+	pub peer_addr: Option<SocketAddr>,
+	pub headers: HeaderMap,
+	pub cookies: HashMap<String, String>,
+	/// The subprotocols listed in `Sec-WebSocket-Protocol`, in the client's order of preference
+	pub protocols: Vec<String>,
+	pub origin: Option<String>,
+}
+
+impl HandshakeRequest {
+	pub(crate) fn new<B>(request: &Request<B>) -> HandshakeRequest {
+		let uri = request.uri();
+		let mut query_params = HashMap::new();
+
+		for (key, value) in form_urlencoded::parse(uri.query().unwrap_or("").as_bytes()) {
+			query_params.insert(key.to_string(), value.to_string());
+		}
+
+		let headers = request.headers();
+
+		HandshakeRequest {
+			path: uri.path().to_owned(),
+			query_params,
+			path_params: HashMap::new(),
+			peer_addr: request.extensions().get().copied(),
+			headers: headers.clone(),
+			cookies: parse_cookies(headers),
+			protocols: parse_protocols(headers),
+			origin: headers.get(ORIGIN).and_then(|origin| origin.to_str().ok()).map(str::to_owned),
+		}
+	}
+
+	pub(crate) fn into_details(self, identity: Option<Arc<dyn Any + Send + Sync>>) -> ConnectionDetails {
+		ConnectionDetails {
+			path: self.path,
+			query_params: self.query_params,
+			path_params: self.path_params,
+			peer_addr: self.peer_addr,
+			headers: self.headers,
+			cookies: self.cookies,
+			protocols: self.protocols,
+			origin: self.origin,
+			identity,
+		}
+	}
+}
+
+/// Collect the cookies of every `Cookie` header. Pairs without a `=` and headers which aren't valid strings are skipped
+fn parse_cookies(headers: &HeaderMap) -> HashMap<String, String> {
+	let mut cookies = HashMap::new();
+
+	for header in headers.get_all(COOKIE) {
+		let Ok(header) = header.to_str() else {
+			continue;
+		};
+
+		for pair in header.split(';') {
+			if let Some((name, value)) = pair.split_once('=') {
+				cookies.insert(name.trim().to_owned(), value.trim().trim_matches('"').to_owned());
+			}
+		}
+	}
+
+	cookies
+}
+
+fn parse_protocols(headers: &HeaderMap) -> Vec<String> {
+	headers
+		.get_all(SEC_WEBSOCKET_PROTOCOL)
+		.iter()
+		.filter_map(|header| header.to_str().ok())
+		.flat_map(|header| header.split(','))
+		.map(str::trim)
+		.filter(|protocol| !protocol.is_empty())
+		.map(str::to_owned)
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+
+	#[test]
+	fn parses_request_parts() {
+		let request = Request::builder()
+			.uri("/docs?id=4&name=a%20b")
+			.header(COOKIE, "session=abc; theme=\"dark\"")
+			.header(COOKIE, "flag; lang = en")
+			.header("x-custom", "1")
+			.header(SEC_WEBSOCKET_PROTOCOL, "v2, v1")
+			.header(SEC_WEBSOCKET_PROTOCOL, "legacy")
+			.header(ORIGIN, "https://example.com")
+			.extension(SocketAddr::from(([10, 0, 0, 1], 4000)))
+			.body(())
+			.unwrap();
+
+		let handshake = HandshakeRequest::new(&request);
+
+		assert_eq!(handshake.path, "/docs");
+		assert_eq!(handshake.peer_addr, Some(SocketAddr::from(([10, 0, 0, 1], 4000))));
+		assert_eq!(handshake.protocols, vec!["v2", "v1", "legacy"]);
+		assert_eq!(handshake.origin.as_deref(), Some("https://example.com"));
+		assert_eq!(handshake.query_params.get("name").map(String::as_str), Some("a b"));
+		assert_eq!(handshake.headers.get("x-custom").unwrap(), "1");
+		assert_eq!(
+			handshake.cookies,
+			HashMap::from([
+				("session".to_owned(), "abc".to_owned()),
+				("theme".to_owned(), "dark".to_owned()),
+				("lang".to_owned(), "en".to_owned()),
+			])
+		);
+	}
+}
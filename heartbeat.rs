@@ -0,0 +1,169 @@
+use std::time::Duration;
+use tokio::time::{interval_at, sleep_until, Instant, Interval, MissedTickBehavior};
+
+/// How often to ping a client, and how long to wait for its pong before treating it as gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Heartbeat {
+	pub interval: Duration,
+	pub timeout: Duration,
+}
+
+/// What a connection should do for its heartbeat
+pub(crate) enum Beat {
+	Ping(Vec<u8>),
+	Dead,
+}
+
+pub(crate) struct HeartbeatState {
+	ticker: Interval,
+	timeout: Duration,
+	/// The payload and send time of the ping which hasn't been answered yet
+	pending: Option<(u64, Instant)>,
+	next_payload: u64,
+	latency: Option<Duration>,
+}
+
+impl HeartbeatState {
+	pub fn new(heartbeat: Heartbeat) -> HeartbeatState {
+		let mut ticker = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+		ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+		HeartbeatState {
+			ticker,
+			timeout: heartbeat.timeout,
+			pending: None,
+			next_payload: 0,
+			latency: None,
+		}
+	}
+
+	/// The round trip time of the last ping which was answered
+	pub fn latency(&self) -> Option<Duration> {
+		self.latency
+	}
+
+	pub fn timeout(&self) -> Duration {
+		self.timeout
+	}
+
+	/// Wait until it's time to ping, or until the pending ping has gone unanswered for too long. Cancel-safe
+	pub async fn next(&mut self) -> Beat {
+		match self.pending {
+			Some((_, sent)) => {
+				sleep_until(sent + self.timeout).await;
+
+				Beat::Dead
+			}
+			None => {
+				self.ticker.tick().await;
+
+				let payload = self.next_payload;
+				self.next_payload += 1;
+				self.pending = Some((payload, Instant::now()));
+
+				Beat::Ping(payload.to_be_bytes().to_vec())
+			}
+		}
+	}
+
+	/// Record a pong from the client. Pongs which don't answer the pending ping are ignored
+	pub fn pong(&mut self, payload: &[u8]) {
+		if let Some((expected, sent)) = self.pending {
+			if payload == expected.to_be_bytes() {
+				self.latency = Some(sent.elapsed());
+				self.pending = None;
+			}
+		}
+	}
+}
+
+/// The next beat of an optional heartbeat, which never comes without one
+pub(crate) async fn next_beat(heartbeat: &mut Option<HeartbeatState>) -> Beat {
+	match heartbeat {
+		Some(heartbeat) => heartbeat.next().await,
+		None => std::future::pending().await,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		tests::{connect_to, next_close_reason},
+		CloseCode, Event, Initiator, SocketServer, HEARTBEAT_REASON,
+	};
+	use futures::StreamExt;
+	use pretty_assertions::assert_eq;
+
+	#[tokio::test(start_paused = true)]
+	async fn pings_and_waits_for_pongs() {
+		let mut heartbeat = HeartbeatState::new(Heartbeat {
+			interval: Duration::from_secs(10),
+			timeout: Duration::from_secs(2),
+		});
+
+		let start = Instant::now();
+
+		let Beat::Ping(payload) = heartbeat.next().await else {
+			panic!("Expected a ping");
+		};
+		assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+		tokio::time::advance(Duration::from_millis(300)).await;
+		heartbeat.pong(b"unrelated");
+		heartbeat.pong(&payload);
+		assert_eq!(heartbeat.latency(), Some(Duration::from_millis(300)));
+
+		assert!(matches!(heartbeat.next().await, Beat::Ping(_)));
+		assert_eq!(start.elapsed(), Duration::from_secs(20));
+
+		assert!(matches!(heartbeat.next().await, Beat::Dead));
+		assert_eq!(start.elapsed(), Duration::from_secs(22));
+	}
+
+	#[tokio::test]
+	async fn measures_latency_of_answering_clients() {
+		let mut server: SocketServer = SocketServer::builder()
+			.heartbeat(Duration::from_millis(10), Duration::from_secs(1))
+			.build()
+			.await
+			.unwrap();
+
+		let (mut connection, mut client) = connect_to(&mut server, "/").await;
+		assert_eq!(connection.latency(), None);
+
+		// Reading is what makes the client answer pings
+		tokio::spawn(async move { while client.next().await.is_some() {} });
+
+		connection.set_tick_interval(Some(Duration::from_millis(100)));
+		assert!(matches!(connection.next_event().await, Some(Event::Connect(_))));
+		assert!(matches!(connection.next_event().await, Some(Event::Tick)));
+
+		assert!(connection.latency().is_some());
+	}
+
+	#[tokio::test]
+	async fn disconnects_clients_which_stop_answering() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (mut connection, mut client) = connect_to(&mut server, "/").await;
+
+		connection.set_heartbeat(Some(Heartbeat {
+			interval: Duration::from_millis(10),
+			timeout: Duration::from_millis(20),
+		}));
+
+		assert!(matches!(connection.next_event().await, Some(Event::Connect(_))));
+
+		match connection.next_event().await {
+			Some(Event::Disconnected { code, reason, initiated_by }) => {
+				assert_eq!(code, CloseCode::Policy);
+				assert_eq!(reason, HEARTBEAT_REASON);
+				assert_eq!(initiated_by, Initiator::Server);
+			}
+			event => panic!("Expected a disconnected event, got {event:?}"),
+		}
+
+		assert!(connection.next_event().await.is_none());
+		assert_eq!(next_close_reason(&mut client).await, HEARTBEAT_REASON);
+	}
+}
@@ -0,0 +1,23 @@
+import { assertEquals, assertThrows } from 'asserts'
+import { applyPatch } from './json_patch.ts'
+
+Deno.test('applyPatch', () => {
+	const document = { title: 'Notes', items: ['a', 'b'], meta: { 'a/b': 1 } }
+
+	assertEquals(applyPatch(document, [{ op: 'replace', path: '/items/1', value: 'c' }]), { title: 'Notes', items: ['a', 'c'], meta: { 'a/b': 1 } })
+	assertEquals(applyPatch(document, [{ op: 'add', path: '/items/-', value: 'c' }, { op: 'remove', path: '/meta/a~1b' }]), {
+		title: 'Notes',
+		items: ['a', 'b', 'c'],
+		meta: {},
+	})
+	assertEquals(applyPatch(document, [{ op: 'move', from: '/title', path: '/name' }, { op: 'copy', from: '/items/0', path: '/items/0' }]), {
+		name: 'Notes',
+		items: ['a', 'a', 'b'],
+		meta: { 'a/b': 1 },
+	})
+	assertEquals(applyPatch(document, [{ op: 'replace', path: '', value: [] }]), [])
+	assertEquals(document, { title: 'Notes', items: ['a', 'b'], meta: { 'a/b': 1 } })
+
+	assertThrows(() => applyPatch(document, [{ op: 'remove', path: '/missing' }]))
+	assertThrows(() => applyPatch(document, [{ op: 'test', path: '/title', value: 'Other' }]))
+})
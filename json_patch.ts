@@ -0,0 +1,111 @@
+export type PatchOperation =
+	| { op: 'add' | 'replace' | 'test'; path: string; value: unknown }
+	| { op: 'remove'; path: string }
+	| { op: 'move' | 'copy'; from: string; path: string }
+
+/** Apply an RFC 6902 JSON Patch. The document is left untouched and the patched copy is returned. Throws if an operation can't be applied */
+export function applyPatch(document: unknown, operations: PatchOperation[]): unknown {
+	let result = structuredClone(document)
+
+	for (const operation of operations) {
+		switch (operation.op) {
+			case 'add':
+				result = add(result, operation.path, structuredClone(operation.value))
+				break
+			case 'remove':
+				result = remove(result, operation.path).document
+				break
+			case 'replace':
+				result = add(remove(result, operation.path).document, operation.path, structuredClone(operation.value))
+				break
+			case 'move': {
+				const removed = remove(result, operation.from)
+				result = add(removed.document, operation.path, removed.value)
+				break
+			}
+			case 'copy':
+				result = add(result, operation.path, structuredClone(get(result, parsePointer(operation.from))))
+				break
+			case 'test':
+				if (!deepEqual(get(result, parsePointer(operation.path)), operation.value)) {
+					throw new Error(`Test operation failed at ${operation.path}`)
+				}
+				break
+			default:
+				throw new Error(`Unknown patch operation: ${JSON.stringify(operation)}`)
+		}
+	}
+
+	return result
+}
+
+const isObject = (value: unknown): value is Record<string, unknown> => typeof value === 'object' && value !== null && !Array.isArray(value)
+
+function parsePointer(pointer: string): string[] {
+	if (pointer === '') return []
+	if (!pointer.startsWith('/')) throw new Error(`Invalid JSON pointer: ${pointer}`)
+
+	return pointer.slice(1).split('/').map((token) => token.replaceAll('~1', '/').replaceAll('~0', '~'))
+}
+
+function arrayIndex(array: unknown[], token: string, allowEnd: boolean): number {
+	const index = /^(0|[1-9][0-9]*)$/.test(token) ? Number(token) : -1
+	const max = allowEnd ? array.length : array.length - 1
+
+	if (index < 0 || index > max) throw new Error(`Invalid array index: ${token}`)
+
+	return index
+}
+
+function get(document: unknown, tokens: string[]): unknown {
+	let current = document
+
+	for (const token of tokens) {
+		if (Array.isArray(current)) current = current[arrayIndex(current, token, false)]
+		else if (isObject(current) && Object.hasOwn(current, token)) current = current[token]
+		else throw new Error(`Path not found: /${tokens.join('/')}`)
+	}
+
+	return current
+}
+
+function add(document: unknown, path: string, value: unknown): unknown {
+	const tokens = parsePointer(path)
+	if (!tokens.length) return value
+
+	const parent = get(document, tokens.slice(0, -1))
+	const key = tokens[tokens.length - 1]
+
+	if (Array.isArray(parent)) parent.splice(key === '-' ? parent.length : arrayIndex(parent, key, true), 0, value)
+	else if (isObject(parent)) parent[key] = value
+	else throw new Error(`Can't add to a value that isn't an object or array: ${path}`)
+
+	return document
+}
+
+function remove(document: unknown, path: string): { document: unknown; value: unknown } {
+	const tokens = parsePointer(path)
+	if (!tokens.length) return { document: null, value: document }
+
+	const parent = get(document, tokens.slice(0, -1))
+	const key = tokens[tokens.length - 1]
+
+	if (Array.isArray(parent)) return { document, value: parent.splice(arrayIndex(parent, key, false), 1)[0] }
+	if (!isObject(parent) || !Object.hasOwn(parent, key)) throw new Error(`Path not found: ${path}`)
+
+	const value = parent[key]
+	delete parent[key]
+
+	return { document, value }
+}
+
+function deepEqual(a: unknown, b: unknown): boolean {
+	if (Array.isArray(a) && Array.isArray(b)) return a.length === b.length && a.every((item, index) => deepEqual(item, b[index]))
+	if (isObject(a) && isObject(b)) {
+		const keys = Object.keys(a)
+
+		return keys.length === Object.keys(b).length && keys.every((key) => Object.hasOwn(b, key) && deepEqual(a[key], b[key]))
+	}
+
+	return a === b
+}
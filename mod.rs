@@ -1,53 +1,252 @@
+mod acceptor;
+mod client;
+mod close_reason;
+mod event_reader;
+mod handshake;
+mod heartbeat;
+mod model_state;
+mod origin;
+mod reader;
+mod registry;
+mod router;
+mod server_builder;
+mod service;
+mod shared_model;
 mod socket_message;
+mod writer;
+#[cfg(feature = "tls")]
+mod tls;
 
+pub use client::{ErrorHandler, SocketClient, SocketClientError, Subscription};
+pub use close_reason::{CloseReason, Initiator};
+pub use event_reader::EventReader;
+pub use handshake::HandshakeRequest;
+pub use heartbeat::Heartbeat;
+pub use origin::OriginPolicy;
+pub use server_builder::{SocketServerBuilder, SocketServerError};
+pub use service::SocketService;
+pub use shared_model::{RoomKey, Rooms, SharedModel};
+pub use socket_message::{SocketMessage, SocketMessageBuilder, SocketMessageError};
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+pub use tungstenite::protocol::frame::coding::CloseCode;
+
+use acceptor::Acceptor;
+use error_stack::ResultExt;
 use futures::{
-	future::{select, Either},
+	future::{pending, select, Either},
 	stream::StreamExt,
-	FutureExt, SinkExt,
+	FutureExt,
 };
-use hyper::{server::conn::http1, service::service_fn, upgrade::Upgraded};
-use hyper_tungstenite::upgrade;
+use http_body_util::BodyExt;
+use hyper::{header::HeaderMap, server::conn::http1, service::service_fn};
+use hyper_tungstenite::is_upgrade_request;
 use hyper_util::rt::TokioIo;
-use serde_json::Value;
-use socket_message::{SocketMessage, SocketMessageBuilder};
-use std::{borrow::Cow, collections::HashMap, convert::Infallible, net::SocketAddr, time::Duration};
+use reader::{Reader, Step};
+use registry::{Registration, Registry};
+use server_builder::{Fallback, ServerOptions};
+use writer::{Socket, Writer};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{to_value, Value};
+use std::{
+	any::Any, collections::HashMap, convert::Infallible, marker::PhantomData, net::SocketAddr, pin::pin,
+	sync::Arc, time::Duration,
+};
+use thiserror::Error;
 use tokio::{
+	io::{AsyncRead, AsyncWrite},
 	net::TcpListener,
-	sync::mpsc::{channel, Receiver},
-	time::sleep,
-};
-use tokio_tungstenite::WebSocketStream;
-use tungstenite::{
-	protocol::{frame::coding::CloseCode, CloseFrame},
-	Message,
+	select,
+	sync::mpsc::{channel, unbounded_channel, Receiver, UnboundedReceiver, UnboundedSender},
+	time::{interval_at, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior},
 };
+use tokio_util::sync::CancellationToken;
+use tungstenite::{error::ProtocolError, Message};
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub struct ConnectionDetails {
 	pub path: String,
 	pub query_params: HashMap<String, String>,
+	/// See `HandshakeRequest::path_params`
+	pub path_params: HashMap<String, String>,
+	/// See `HandshakeRequest::peer_addr`
+	pub peer_addr: Option<SocketAddr>,
+	pub headers: HeaderMap,
+	pub cookies: HashMap<String, String>,
+	/// The subprotocols listed in `Sec-WebSocket-Protocol`, in the client's order of preference
+	pub protocols: Vec<String>,
+	pub origin: Option<String>,
+	identity: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl ConnectionDetails {
+	/// The identity returned by the `SocketServerBuilder::authenticate` callback, if it has type `T`
+	pub fn identity<T: Any>(&self) -> Option<&T> {
+		self.identity.as_deref()?.downcast_ref()
+	}
 }
 
 #[derive(Debug)]
-pub enum Event {
-	Connect(ConnectionDetails),
-	Update(Value),
+pub enum Event<E = Value> {
+	Connect(Box<ConnectionDetails>),
+	Update(E),
+	/// The interval set with `Connection::set_tick_interval` has elapsed
+	Tick,
+	/// The connection has ended, because of the side which sent the first close frame. When the client dropped the
+	/// connection without one, the code is `CloseCode::Abnormal`. No events follow
+	Disconnected {
+		code: CloseCode,
+		reason: String,
+		initiated_by: Initiator,
+	},
+}
+
+enum Signal {
+	Model(Value),
+	Message(String),
+	/// A client asked to catch up
+	Sync(Option<u64>),
+	/// A client sent an event with this pin, through an `EventReader`
+	Pin(Option<Uuid>),
+	Ping(Vec<u8>),
+	Close(CloseReason),
 }
 
-pub struct Connection {
-	connection_details: Option<ConnectionDetails>,
-	socket: WebSocketStream<TokioIo<Upgraded>>,
-	last_value: Option<Value>,
-	client_pin: Option<Uuid>,
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(50);
+const INACTIVITY_REASON: &str = "Connection closed due to inactivity. When another operation is necessary, reconnect";
+const HEARTBEAT_REASON: &str = "Connection closed because the client stopped answering pings";
+const DROPPED_REASON: &str = "The client dropped the connection without a close frame";
+
+/// What a client asked for with a socket message
+enum ClientMessage<E> {
+	Ignore,
+	/// The client sent a close frame with this code and reason, or dropped the connection
+	Closed(CloseCode, String),
+	Pong(Vec<u8>),
+	Sync(Option<u64>),
+	Event(Option<Uuid>, E),
+	/// The connection should be closed with this reason
+	Invalid(CloseReason),
+}
+
+#[derive(Debug, Error)]
+pub enum SendError {
+	#[error("Failed to serialize the model")]
+	Serialize,
+
+	#[error("The connection has already been closed")]
+	Closed,
+}
+
+/// A cloneable handle that can push models to a connection, or close it, from any task. Signals are processed
+/// while the connection is awaiting `Connection::next_event`
+pub struct ConnectionHandle<M = Value> {
+	signals: UnboundedSender<Signal>,
+	model: PhantomData<fn(&M)>,
+}
+
+/// The sending half of a split connection. Models are written by a task of their own, so they go out while the
+/// `EventReader` is waiting for events, or isn't being polled at all
+pub type ModelSender<M = Value> = ConnectionHandle<M>;
+
+impl<M> Clone for ConnectionHandle<M> {
+	fn clone(&self) -> Self {
+		ConnectionHandle {
+			signals: self.signals.clone(),
+			model: PhantomData,
+		}
+	}
 }
 
-impl Connection {
-	pub async fn next_event_with_timeout(&mut self, timeout: Duration) -> Option<Event> {
+impl<M: Serialize> ConnectionHandle<M> {
+	/// Queue a model to be sent to the client
+	pub fn send(&self, model: &M) -> error_stack::Result<(), SendError> {
+		let model = to_value(model).change_context(SendError::Serialize)?;
+
+		self.signals.send(Signal::Model(model)).map_err(|_| SendError::Closed)?;
+
+		Ok(())
+	}
+
+	/// Close the connection normally with a reason. Returns false if the connection has already been dropped
+	pub fn close<S: Into<String>>(&self, reason: S) -> bool {
+		self.close_with(CloseReason::Normal(reason.into()))
+	}
+
+	/// Close the connection with the close code of `reason`. Returns false if the connection has already been dropped
+	pub fn close_with(&self, reason: CloseReason) -> bool {
+		self.signals.send(Signal::Close(reason)).is_ok()
+	}
+}
+
+pub struct Connection<M = Value, E = Value> {
+	reader: Reader<E>,
+	writer: Writer,
+	signals_sender: UnboundedSender<Signal>,
+	signals: UnboundedReceiver<Signal>,
+	registration: Registration,
+	types: PhantomData<fn(&M) -> E>,
+}
+
+impl<M: Serialize, E: DeserializeOwned> Connection<M, E> {
+	fn new(
+		socket: Socket,
+		connection_details: ConnectionDetails,
+		idle_timeout: Option<Duration>,
+		heartbeat: Option<Heartbeat>,
+		registry: &Registry,
+	) -> Connection<M, E> {
+		let (signals_sender, signals) = unbounded_channel();
+		let registration = registry.register(signals_sender.clone());
+		let (sink, stream) = socket.split();
+		let writer = Writer::new(sink);
+
+		Connection {
+			reader: Reader::new(stream, connection_details, idle_timeout, heartbeat, writer.closed()),
+			writer,
+			signals_sender,
+			signals,
+			registration,
+			types: PhantomData,
+		}
+	}
+
+	pub fn handle(&self) -> ConnectionHandle<M> {
+		ConnectionHandle {
+			signals: self.signals_sender.clone(),
+			model: PhantomData,
+		}
+	}
+
+	/// Make `next_event` yield `Event::Tick` every `period`. Pass `None` to stop ticking
+	pub fn set_tick_interval(&mut self, period: Option<Duration>) {
+		self.reader.set_tick_interval(period);
+	}
+
+	/// Make `next_event` close the connection when the client sends nothing for `timeout`. Pass `None` to never close
+	/// idle connections
+	pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+		self.reader.set_idle_timeout(timeout);
+	}
+
+	/// Ping the client every `heartbeat.interval`, and close the connection with `Event::Disconnected` when a ping isn't
+	/// answered within `heartbeat.timeout`. Pass `None` to stop pinging
+	pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+		self.reader.set_heartbeat(heartbeat);
+	}
+
+	/// The round trip time of the last ping the client answered. `None` until a heartbeat is set and answered
+	pub fn latency(&self) -> Option<Duration> {
+		self.reader.latency()
+	}
+
+	/// Like `next_event`, but closes the connection when no event arrives within `timeout`
+	pub async fn next_event_with_timeout(&mut self, timeout: Duration) -> Option<Event<E>> {
 		let res = {
-			let foo = select(self.next_event().boxed(), sleep(timeout).boxed()).await;
+			let next = select(self.next_event().boxed(), sleep(timeout).boxed()).await;
 
-			match foo {
+			match next {
 				Either::Left((event, _)) => Some(event),
 				Either::Right(_) => None,
 			}
@@ -55,195 +254,819 @@ impl Connection {
 
 		match res {
 			Some(event) => event,
-			None => {
-				self.close("Connection closed due to inactivity. When another operation is necessary, reconnect")
-					.await;
-
-				None
-			}
+			None => Some(self.end(CloseReason::Policy(INACTIVITY_REASON.to_owned())).await),
 		}
 	}
 
-	pub async fn next_event(&mut self) -> Option<Event> {
-		None
+	/// Get the next event for this connection. Socket events are merged with models pushed and close requests made
+	/// through a `ConnectionHandle`, and with ticks. However the connection ends, `Event::Disconnected` says why, and
+	/// `None` is returned after it
+	pub async fn next_event(&mut self) -> Option<Event<E>> {
+		self.read(false).await
 	}
 
-	pub async fn next_socket_event(&mut self) -> Option<Event> {
-		match self.connection_details.take() {
-			Some(details) => return Some(Event::Connect(details)),
-			None => (),
-		}
+	/// Like `next_event`, but only for events from the client
+	pub async fn next_socket_event(&mut self) -> Option<Event<E>> {
+		self.read(true).await
+	}
 
-		let model = loop {
-			let message = match self.socket.next().await {
-				Some(message) => match message {
-					Ok(message) => message,
-					Err(_) => {
-						self.close("Error parsing incoming message").await;
-						return None;
-					}
-				},
-				None => return None,
-			};
+	async fn read(&mut self, socket_only: bool) -> Option<Event<E>> {
+		loop {
+			// `E` isn't necessarily `Send`, so events are returned before anything is awaited
+			let (signal, deadline) = match self.next_step(socket_only).await {
+				Step::Event(event) => return event,
+				Step::Update(pin, event) => {
+					self.writer.client_pin = pin;
 
-			let socket_message = match message {
-				Message::Text(text) => match SocketMessage::parse(text) {
-					Ok(message) => message,
-					Err(_) => {
-						self.close("Failed to parse socket message").await;
-						return None;
-					}
-				},
-				Message::Ping(_) | Message::Pong(_) => continue,
-				Message::Close(_) => return None,
-				_ => {
-					self.close("Received invalid message").await;
-					return None;
+					return Some(Event::Update(event));
 				}
+				Step::Signal(signal) => (signal, None),
+				Step::Close(reason, deadline) => (Signal::Close(reason), deadline),
 			};
 
-			let prefix = socket_message.get_prefix();
+			// Once the signal has closed the connection, the reader reports it on the next step
+			match deadline {
+				Some(deadline) => {
+					let _ = timeout(deadline, self.writer.handle_signal(signal)).await;
+				}
+				None => {
+					self.writer.handle_signal(signal).await;
+				}
+			}
+		}
+	}
 
-			if prefix == "sync" {
-				match self.last_value.take() {
-					Some(model) => self.send(model).await,
-					None => (),
-				};
+	async fn next_step(&mut self, socket_only: bool) -> Step<E> {
+		match socket_only {
+			true => self.reader.next_socket().await,
+			false => self.reader.next(Some(&mut self.signals)).await,
+		}
+	}
 
-				continue;
-			} else if prefix == "event" {
-				let pin = match socket_message.get_context() {
-					Some(context) => match Uuid::parse_str(context) {
-						Ok(uuid) => Some(uuid),
-						Err(_) => {
-							self.close("Expected to receive a valid UUID a context to 'event' message").await;
+	/// Send a model to the client. Once the connection has joined a `SharedModel`, this updates the shared model for all
+	/// of its members instead
+	pub async fn send(&mut self, model: &M) -> error_stack::Result<(), SendError> {
+		let model = to_value(model).change_context(SendError::Serialize)?;
 
-							return None;
-						}
-					},
-					None => None,
-				};
+		self.writer.send_value(model).await
+	}
 
-				self.client_pin = pin;
+	/// Close the connection normally with a reason. The next call to `next_event` returns `Event::Disconnected`
+	pub async fn close<S: Into<String>>(&mut self, reason: S) {
+		self.close_with(CloseReason::Normal(reason.into())).await
+	}
 
-				match socket_message.get_body() {
-					Some(body) => break body,
-					None => {
-						self.close("Expected to receive JSON body with 'event' message").await;
+	/// Close the connection with the close code of `reason`. The next call to `next_event` returns
+	/// `Event::Disconnected`
+	pub async fn close_with(&mut self, reason: CloseReason) {
+		self.writer.close(reason).await
+	}
 
-						return None;
-					}
-				}
-			} else {
-				self.close("Invalid message prefix: ".to_owned() + prefix + ". Expected 'sync' or 'event'")
-					.await;
+	/// Close the connection, and report it as disconnected by the server
+	async fn end(&mut self, reason: CloseReason) -> Event<E> {
+		self.close_with(reason.clone()).await;
+
+		self.reader.end(&reason)
+	}
 
-				return None;
+	/// Close a connection that never made it to the application, preferring the reason of a pending shutdown
+	async fn reject(mut self, reason: CloseReason) {
+		match self.signals.try_recv() {
+			Ok(Signal::Close(pending)) => self.close_with(pending).await,
+			_ => self.close_with(reason).await,
+		}
+	}
+}
+
+/// The `Event::Disconnected` for a connection which the server closed
+fn server_disconnected<E>(reason: &CloseReason) -> Event<E> {
+	Event::Disconnected {
+		code: reason.code(),
+		reason: reason.message().to_owned(),
+		initiated_by: Initiator::Server,
+	}
+}
+
+/// The `Event::Disconnected` for a connection which ended on the client's side. When the server had already closed it,
+/// the client was only answering
+fn client_disconnected<E>(closed: Option<&CloseReason>, code: CloseCode, reason: String) -> Event<E> {
+	match closed {
+		Some(reason) => server_disconnected(reason),
+		None => Event::Disconnected {
+			code,
+			reason,
+			initiated_by: Initiator::Client,
+		},
+	}
+}
+
+fn parse_client_message<E: DeserializeOwned>(message: Option<Result<Message, tungstenite::Error>>) -> ClientMessage<E> {
+	let message = match message {
+		Some(Ok(message)) => message,
+		Some(Err(
+			tungstenite::Error::Io(_)
+			| tungstenite::Error::ConnectionClosed
+			| tungstenite::Error::AlreadyClosed
+			| tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake),
+		))
+		| None => return ClientMessage::Closed(CloseCode::Abnormal, DROPPED_REASON.to_owned()),
+		Some(Err(_)) => return ClientMessage::Invalid(CloseReason::Protocol("Error parsing incoming message".to_owned())),
+	};
+
+	let socket_message = match message {
+		Message::Text(text) => match SocketMessage::parse(text) {
+			Ok(message) => message,
+			Err(_) => return ClientMessage::Invalid(CloseReason::Protocol("Failed to parse socket message".to_owned())),
+		},
+		Message::Ping(_) => return ClientMessage::Ignore,
+		Message::Pong(payload) => return ClientMessage::Pong(payload),
+		Message::Close(Some(frame)) => return ClientMessage::Closed(frame.code, frame.reason.into_owned()),
+		Message::Close(None) => return ClientMessage::Closed(CloseCode::Status, String::new()),
+		_ => return ClientMessage::Invalid(CloseReason::Unsupported("Received invalid message".to_owned())),
+	};
+
+	let prefix = socket_message.get_prefix();
+
+	if prefix == "sync" {
+		match socket_message.get_context().map(str::parse) {
+			Some(Ok(version)) => ClientMessage::Sync(Some(version)),
+			Some(Err(_)) => ClientMessage::Invalid(CloseReason::Protocol(
+				"Expected to receive a model version as context to 'sync' message".to_owned(),
+			)),
+			None => ClientMessage::Sync(None),
+		}
+	} else if prefix == "event" {
+		let pin = match socket_message.get_context().map(Uuid::parse_str) {
+			Some(Ok(uuid)) => Some(uuid),
+			Some(Err(_)) => {
+				return ClientMessage::Invalid(CloseReason::Protocol(
+					"Expected to receive a valid UUID a context to 'event' message".to_owned(),
+				))
 			}
+			None => None,
 		};
 
-		Some(Event::Update(model))
+		match socket_message.body_as() {
+			Ok(Some(event)) => ClientMessage::Event(pin, event),
+			Ok(None) => ClientMessage::Invalid(CloseReason::Protocol(
+				"Expected to receive JSON body with 'event' message".to_owned(),
+			)),
+			Err(error) => match error.current_context() {
+				SocketMessageError::MismatchedBody(error) => ClientMessage::Invalid(CloseReason::Unsupported(format!(
+					"Received an 'event' body that doesn't match the expected type: {error}"
+				))),
+				error => ClientMessage::Invalid(CloseReason::Protocol(format!(
+					"Received an 'event' body that isn't valid JSON: {error}"
+				))),
+			},
+		}
+	} else {
+		ClientMessage::Invalid(CloseReason::Protocol(
+			"Invalid message prefix: ".to_owned() + prefix + ". Expected 'sync' or 'event'",
+		))
 	}
+}
 
-	pub async fn send(&mut self, state: Value) {
-		let pin_string = match self.client_pin {
-			Some(pin) => pin.to_string(),
-			None => "".to_owned(),
-		};
+/// Whether a socket message shows that the client is being used. Pongs are answered automatically, so they don't
+/// count, or heartbeats would keep idle connections open forever
+fn is_activity(message: &Option<Result<Message, tungstenite::Error>>) -> bool {
+	!matches!(message, Some(Ok(Message::Pong(_))))
+}
 
-		// This should never panic because we are definitely setting it up correctly here
-		let message = SocketMessageBuilder::new("model").context(pin_string).body(&state).build().unwrap();
+fn ticker(period: Duration) -> Interval {
+	let mut ticker = interval_at(Instant::now() + period, period);
+	ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-		let _ = self.socket.send(Message::Text(message.to_string())).await;
+	ticker
+}
 
-		self.last_value.replace(state);
+async fn next_tick(ticker: &mut Option<Interval>) {
+	match ticker {
+		Some(ticker) => {
+			ticker.tick().await;
+		}
+		None => pending().await,
 	}
+}
 
-	pub async fn close<S: Into<String>>(&mut self, reason: S) {
-		let _ = self
-			.socket
-			.close(Some(CloseFrame {
-				code: CloseCode::Normal,
-				reason: Cow::Owned(reason.into()),
-			}))
-			.await;
+/// Serve HTTP on a single stream, turning upgrade requests into connections and giving other requests to `fallback`
+async fn serve_http<M, E, S>(
+	http: http1::Builder,
+	stream: S,
+	peer_addr: SocketAddr,
+	acceptor: Acceptor<M, E>,
+	fallback: Option<Fallback>,
+	shutdown: CancellationToken,
+) where
+	M: Serialize + 'static,
+	E: DeserializeOwned + 'static,
+	S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+	let connection = http
+		.serve_connection(
+			TokioIo::new(stream),
+			service_fn(move |mut request| {
+				let acceptor = acceptor.clone();
+				let fallback = fallback.clone();
+				request.extensions_mut().insert(peer_addr);
+
+				async move {
+					let response = match fallback {
+						Some(fallback) if !is_upgrade_request(&request) => fallback(request).await,
+						_ => acceptor.accept(request).await.map(|body| body.map_err(|never| match never {}).boxed_unsync()),
+					};
+
+					Ok::<_, Infallible>(response)
+				}
+			}),
+		)
+		.with_upgrades();
+
+	let mut connection = pin!(connection);
+
+	let result = select! {
+		result = connection.as_mut() => result,
+		_ = shutdown.cancelled() => {
+			connection.as_mut().graceful_shutdown();
+			connection.await
+		}
+	};
+
+	if let Err(error) = result {
+		log::debug!("Failed to serve HTTP connection: {error}");
 	}
 }
 
-pub struct SocketServer {
-	connections_receiver: Receiver<Connection>,
+async fn idle(timeout: Option<Duration>, since: Instant) {
+	match timeout {
+		Some(timeout) => sleep_until(since + timeout).await,
+		None => pending().await,
+	}
 }
 
-impl SocketServer {
-	pub async fn new(port: u16) -> SocketServer {
-		let addr = SocketAddr::from(([127, 0, 0, 1], port));
-		let listener = TcpListener::bind(addr).await.unwrap();
+pub struct SocketServer<M = Value, E = Value> {
+	connections_receiver: Receiver<Connection<M, E>>,
+	local_addr: Option<SocketAddr>,
+	acceptor: Acceptor<M, E>,
+	registry: Registry,
+	drained: Receiver<()>,
+	shutdown: CancellationToken,
+}
+
+impl<M: Serialize + 'static, E: DeserializeOwned + 'static> SocketServer<M, E> {
+	/// Listen on a port of 127.0.0.1. Use `SocketServer::builder` for more options
+	pub async fn new(port: u16) -> error_stack::Result<SocketServer<M, E>, SocketServerError> {
+		SocketServerBuilder::new().bind(([127, 0, 0, 1], port)).build().await
+	}
+
+	pub fn builder() -> SocketServerBuilder<M, E> {
+		SocketServerBuilder::new()
+	}
 
+	/// A server which only receives connections through `SocketServer::service`
+	fn unbound(options: &ServerOptions<M, E>) -> SocketServer<M, E> {
+		let (sender, receiver) = channel(options.queue_size);
+		let (drain, drained) = channel(1);
+		let registry = Registry::new(drain);
+
+		SocketServer {
+			connections_receiver: receiver,
+			local_addr: None,
+			acceptor: Acceptor {
+				sender,
+				router: options.router.clone(),
+				registry: registry.clone(),
+				idle_timeout: options.idle_timeout,
+				heartbeat: options.heartbeat,
+				origin_policy: options.origin_policy.clone(),
+				authenticate: options.authenticate.clone(),
+			},
+			registry,
+			drained,
+			shutdown: CancellationToken::new(),
+		}
+	}
+
+	fn serve(listener: TcpListener, local_addr: SocketAddr, options: ServerOptions<M, E>) -> SocketServer<M, E> {
+		// Keep-alive must stay enabled, since hyper answers upgrades with `Connection: close` otherwise
 		let mut http = http1::Builder::new();
 		http.keep_alive(true);
 
-		let (sender, receiver) = channel(100);
+		let mut server = SocketServer::unbound(&options);
+		server.local_addr = Some(local_addr);
+
+		let acceptor = server.acceptor.clone();
+		let shutdown = server.shutdown.clone();
+		let fallback = options.fallback;
+		#[cfg(feature = "tls")]
+		let tls = options.tls;
+		#[cfg(feature = "tls")]
+		let tls_handshake_timeout = options.tls_handshake_timeout;
 
 		tokio::spawn(async move {
 			loop {
-				let (stream, _) = listener.accept().await.unwrap();
-				let connection_sender = sender.clone();
-
-				let connection = http
-					.serve_connection(
-						TokioIo::new(stream),
-						service_fn(move |mut request| {
-							let single_sender = connection_sender.clone();
-
-							async move {
-								let uri = request.uri();
-								let path = uri.path().to_owned();
-								let mut query_params = HashMap::new();
-
-								for (key, value) in form_urlencoded::parse(uri.query().unwrap_or("").as_bytes()) {
-									query_params.insert(key.to_string(), value.to_string());
-								}
-
-								let (response, hyper_socket) = upgrade(&mut request, None).unwrap();
-
-								tokio::spawn(async move {
-									let socket = hyper_socket.await.unwrap();
-
-									match single_sender
-										.clone()
-										.send(Connection {
-											connection_details: Some(ConnectionDetails { path, query_params }),
-											socket,
-											client_pin: None,
-											last_value: None,
-										})
-										.await
-									{
-										Ok(_) => (),
-										Err(mut error) => {
-											error.0.close("Failed to queue connection").await;
-										}
-									};
-								});
-
-								Ok::<_, Infallible>(response)
-							}
-						}),
-					)
-					.with_upgrades();
+				let accepted = select! {
+					accepted = listener.accept() => accepted,
+					_ = shutdown.cancelled() => break,
+				};
+
+				let (stream, peer_addr) = match accepted {
+					Ok(accepted) => accepted,
+					// Errors here are specific to the connection that failed, or are resource limits which may clear up
+					// after a moment, so keep accepting
+					Err(_) => {
+						sleep(ACCEPT_ERROR_DELAY).await;
+
+						continue;
+					}
+				};
+
+				let http = http.clone();
+				let acceptor = acceptor.clone();
+				let fallback = fallback.clone();
+				let shutdown = shutdown.clone();
+				#[cfg(feature = "tls")]
+				let tls = tls.clone();
 
 				tokio::spawn(async move {
-					connection.await.unwrap();
+					#[cfg(feature = "tls")]
+					if let Some(tls) = tls {
+						let handshake = select! {
+							handshake = timeout(tls_handshake_timeout, tls.accept(stream)) => handshake,
+							_ = shutdown.cancelled() => return,
+						};
+
+						// The stream is dropped along with the handshake when it fails or times out
+						match handshake {
+							Ok(Ok(stream)) => serve_http(http, stream, peer_addr, acceptor, fallback, shutdown).await,
+							Ok(Err(error)) => log::debug!("TLS handshake failed: {error}"),
+							Err(_) => log::debug!("TLS handshake from {peer_addr} timed out"),
+						}
+
+						return;
+					}
+
+					serve_http(http, stream, peer_addr, acceptor, fallback, shutdown).await;
 				});
 			}
 		});
 
-		SocketServer {
-			connections_receiver: receiver,
-		}
+		server
 	}
 
-	pub async fn accept_connection(&mut self) -> Option<Connection> {
+	/// The address the server is listening on. Useful when the server was started on port 0. `None` when the server
+	/// was built with `SocketServerBuilder::build_unbound`
+	pub fn local_addr(&self) -> Option<SocketAddr> {
+		self.local_addr
+	}
+
+	/// A tower `Service` which upgrades requests into connections for `SocketServer::accept_connection`
+	pub fn service(&self) -> SocketService<M, E> {
+		SocketService::new(self.acceptor.clone())
+	}
+
+	pub async fn accept_connection(&mut self) -> Option<Connection<M, E>> {
 		self.connections_receiver.recv().await
 	}
+
+	/// Stop accepting connections and close every open connection with `CloseReason::Away(reason)`. Connections
+	/// close once they process the request in `Connection::next_event`, or are dropped. Resolves when all connections
+	/// are gone, returning true, or when `deadline` passes, returning false
+	pub async fn shutdown<S: Into<String>>(&mut self, reason: S, deadline: Duration) -> bool {
+		let reason = reason.into();
+
+		self.shutdown.cancel();
+		self.connections_receiver.close();
+
+		while let Ok(mut connection) = self.connections_receiver.try_recv() {
+			connection.close_with(CloseReason::Away(reason.clone())).await;
+		}
+
+		self.registry.close_all(CloseReason::Away(reason));
+
+		timeout(deadline, self.drained.recv()).await.is_ok()
+	}
+}
+
+impl<M, E> Drop for SocketServer<M, E> {
+	fn drop(&mut self) {
+		self.shutdown.cancel();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::SinkExt;
+	use pretty_assertions::assert_eq;
+	use serde::Deserialize;
+	use serde_json::json;
+	use std::fmt::Debug;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpStream,
+	};
+	use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+	use tungstenite::protocol::CloseFrame;
+
+	pub(crate) type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+	pub(crate) async fn connect_to<M, E>(server: &mut SocketServer<M, E>, path: &str) -> (Connection<M, E>, Client)
+	where
+		M: Serialize + 'static,
+		E: DeserializeOwned + 'static,
+	{
+		let (client, _) = connect_async(format!("ws://{}{}", server.local_addr().unwrap(), path)).await.unwrap();
+		let connection = server.accept_connection().await.unwrap();
+
+		(connection, client)
+	}
+
+	async fn connect(path: &str) -> (SocketServer, Connection, Client) {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, client) = connect_to(&mut server, path).await;
+
+		(server, connection, client)
+	}
+
+	/// Send a raw HTTP request, which should ask for the connection to be closed, and read the whole response
+	pub(crate) async fn raw_request(addr: SocketAddr, request: &str) -> String {
+		let mut stream = TcpStream::connect(addr).await.unwrap();
+		stream.write_all(request.as_bytes()).await.unwrap();
+
+		let mut response = Vec::new();
+		stream.read_to_end(&mut response).await.unwrap();
+
+		String::from_utf8(response).unwrap()
+	}
+
+	pub(crate) async fn next_text<S: AsyncRead + AsyncWrite + Unpin>(client: &mut WebSocketStream<S>) -> String {
+		loop {
+			match client.next().await.unwrap().unwrap() {
+				Message::Text(text) => return text,
+				Message::Ping(_) | Message::Pong(_) => continue,
+				message => panic!("Expected a text message, got {message:?}"),
+			}
+		}
+	}
+
+	/// The code and initiator of an `Event::Disconnected`
+	pub(crate) fn disconnection<E: Debug>(event: Option<Event<E>>) -> (CloseCode, Initiator) {
+		match event {
+			Some(Event::Disconnected { code, initiated_by, .. }) => (code, initiated_by),
+			event => panic!("Expected a disconnected event, got {event:?}"),
+		}
+	}
+
+	pub(crate) async fn next_close_reason(client: &mut Client) -> String {
+		loop {
+			match client.next().await.unwrap().unwrap() {
+				Message::Close(frame) => return frame.unwrap().reason.into_owned(),
+				Message::Ping(_) | Message::Pong(_) => continue,
+				message => panic!("Expected a close message, got {message:?}"),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn yields_connect_and_update_events() {
+		let (_server, mut connection, mut client) = connect("/docs?id=4&name=a%20b").await;
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => {
+				assert_eq!(details.path, "/docs");
+				assert_eq!(details.query_params.get("id").map(String::as_str), Some("4"));
+				assert_eq!(details.query_params.get("name").map(String::as_str), Some("a b"));
+			}
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+
+		let pin = Uuid::from_u128(0x936da01f9abd4d9d80c702af85c822a8);
+		client.send(Message::Text(format!("event({pin}) {{\"count\": 1}}"))).await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Update(body)) => assert_eq!(body, json!({ "count": 1 })),
+			event => panic!("Expected an update event, got {event:?}"),
+		}
+
+		connection.send(&json!({ "total": 1 })).await.unwrap();
+		assert_eq!(next_text(&mut client).await, format!("model(1:{pin}) {{\"total\":1}}"));
+	}
+
+	#[tokio::test]
+	async fn sync_resends_the_last_model() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		connection.send(&json!([1, 2])).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) [1,2]");
+
+		client.send(Message::Text("sync".to_owned())).await.unwrap();
+		client.send(Message::Text("event() null".to_owned())).await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Update(body)) => assert_eq!(body, Value::Null),
+			event => panic!("Expected an update event, got {event:?}"),
+		}
+
+		assert_eq!(next_text(&mut client).await, "model(1) [1,2]");
+	}
+
+	#[tokio::test]
+	async fn sends_patches_unless_the_snapshot_is_smaller() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		let mut document = json!({ "items": (0..10).map(|n| format!("item {n}")).collect::<Vec<_>>() });
+		connection.send(&document).await.unwrap();
+		assert!(next_text(&mut client).await.starts_with("model(1) {\"items\":[\"item 0\","));
+
+		document["items"][2] = json!("changed");
+		connection.send(&document).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "patch(2) [{\"op\":\"replace\",\"path\":\"/items/2\",\"value\":\"changed\"}]");
+
+		connection.send(&json!([])).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(3) []");
+
+		client.send(Message::Text("sync".to_owned())).await.unwrap();
+		client.send(Message::Text("event() null".to_owned())).await.unwrap();
+		connection.next_event().await.unwrap();
+
+		assert_eq!(next_text(&mut client).await, "model(3) []");
+	}
+
+	#[tokio::test]
+	async fn sync_sends_only_missed_patches() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		let mut document = json!({ "items": (0..10).map(|n| format!("item {n}")).collect::<Vec<_>>() });
+		connection.send(&document).await.unwrap();
+
+		for version in 2..=3 {
+			document["items"][0] = json!(version);
+			connection.send(&document).await.unwrap();
+		}
+
+		for _ in 1..=3 {
+			next_text(&mut client).await;
+		}
+
+		client.send(Message::Text("sync(1)".to_owned())).await.unwrap();
+		client.send(Message::Text("sync(3)".to_owned())).await.unwrap();
+		client.send(Message::Text("sync(7)".to_owned())).await.unwrap();
+		client.send(Message::Text("event() null".to_owned())).await.unwrap();
+		connection.next_event().await.unwrap();
+
+		assert_eq!(next_text(&mut client).await, "patch(2) [{\"op\":\"replace\",\"path\":\"/items/0\",\"value\":2}]");
+		assert_eq!(next_text(&mut client).await, "patch(3) [{\"op\":\"replace\",\"path\":\"/items/0\",\"value\":3}]");
+		assert!(next_text(&mut client).await.starts_with("model(3) {\"items\":[3,"));
+
+		client.send(Message::Text("sync(latest)".to_owned())).await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Protocol, Initiator::Server));
+		assert_eq!(
+			next_close_reason(&mut client).await,
+			"Expected to receive a model version as context to 'sync' message"
+		);
+	}
+
+	#[tokio::test]
+	async fn handle_pushes_models_while_waiting() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		let handle = connection.handle();
+		let events = tokio::spawn(async move { disconnection(connection.next_event().await) });
+
+		handle.send(&json!({ "pushed": true })).unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) {\"pushed\":true}");
+
+		assert!(handle.close("Shutting down"));
+		assert_eq!(next_close_reason(&mut client).await, "Shutting down");
+		assert_eq!(events.await.unwrap(), (CloseCode::Normal, Initiator::Server));
+	}
+
+	#[tokio::test]
+	async fn ticks_while_idle() {
+		let (_server, mut connection, _client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		connection.set_tick_interval(Some(Duration::from_millis(10)));
+
+		assert!(matches!(connection.next_event().await, Some(Event::Tick)));
+		assert!(matches!(connection.next_event().await, Some(Event::Tick)));
+	}
+
+	#[tokio::test]
+	async fn ends_when_client_closes() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		client
+			.close(Some(CloseFrame {
+				code: CloseCode::Normal,
+				reason: "Tab closed".into(),
+			}))
+			.await
+			.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Disconnected { code, reason, initiated_by }) => {
+				assert_eq!(code, CloseCode::Normal);
+				assert_eq!(reason, "Tab closed");
+				assert_eq!(initiated_by, Initiator::Client);
+			}
+			event => panic!("Expected a disconnected event, got {event:?}"),
+		}
+
+		assert!(connection.next_event().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn reports_dropped_connections_as_abnormal() {
+		let (_server, mut connection, client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		drop(client);
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Abnormal, Initiator::Client));
+		assert!(connection.next_event().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn reports_closes_made_by_the_application() {
+		let (_server, mut connection, _client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		connection.close("Done").await;
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Normal, Initiator::Server));
+		assert!(connection.next_event().await.is_none());
+	}
+
+	#[tokio::test]
+	async fn closes_on_invalid_prefix() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		client.send(Message::Text("unknown() {}".to_owned())).await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Protocol, Initiator::Server));
+		assert!(connection.next_event().await.is_none());
+
+		let frame = next_close_frame(&mut client).await;
+		assert_eq!(frame.code, CloseCode::Protocol);
+		assert_eq!(frame.reason, "Invalid message prefix: unknown. Expected 'sync' or 'event'");
+	}
+
+	#[tokio::test]
+	async fn closes_on_invalid_json_bodies() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		client.send(Message::Text("event() {\"unfinished\": ".to_owned())).await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Protocol, Initiator::Server));
+		assert_eq!(
+			next_close_reason(&mut client).await,
+			"Received an 'event' body that isn't valid JSON: The body isn't valid JSON, at line 1 column 15 of the body"
+		);
+	}
+
+	#[tokio::test]
+	async fn closes_on_binary_messages_as_unsupported() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		client.send(Message::Binary(vec![1, 2, 3])).await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Unsupported, Initiator::Server));
+		assert_eq!(next_close_frame(&mut client).await.code, CloseCode::Unsupported);
+	}
+
+	#[tokio::test]
+	async fn closes_with_application_codes() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		assert!(connection.handle().close_with(CloseReason::Application(4001, "Kicked".to_owned())));
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Library(4001), Initiator::Server));
+
+		let frame = next_close_frame(&mut client).await;
+		assert_eq!(frame.code, CloseCode::Library(4001));
+		assert_eq!(frame.reason, "Kicked");
+	}
+
+	async fn next_close_frame(client: &mut Client) -> CloseFrame<'static> {
+		loop {
+			match client.next().await.unwrap().unwrap() {
+				Message::Close(frame) => return frame.unwrap().into_owned(),
+				Message::Ping(_) | Message::Pong(_) => continue,
+				message => panic!("Expected a close message, got {message:?}"),
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn shutdown_closes_every_connection() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let addr = server.local_addr().unwrap();
+
+		let (mut connection, mut client) = connect_to(&mut server, "/").await;
+		let handler = tokio::spawn(async move { while connection.next_event().await.is_some() {} });
+
+		let (mut queued_client, _) = connect_async(format!("ws://{addr}")).await.unwrap();
+
+		assert!(server.shutdown("Restarting", Duration::from_secs(5)).await);
+		handler.await.unwrap();
+
+		for client in [&mut client, &mut queued_client] {
+			let frame = next_close_frame(client).await;
+
+			assert_eq!(frame.code, CloseCode::Away);
+			assert_eq!(frame.reason, "Restarting");
+		}
+
+		assert!(server.accept_connection().await.is_none());
+		assert!(connect_async(format!("ws://{addr}")).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn shutdown_gives_up_after_the_deadline() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let (connection, _client) = connect_to(&mut server, "/").await;
+
+		assert!(!server.shutdown("Restarting", Duration::from_millis(20)).await);
+		drop(connection);
+	}
+
+	#[tokio::test]
+	async fn dropping_the_server_stops_listening() {
+		let server: SocketServer = SocketServer::new(0).await.unwrap();
+		let addr = server.local_addr().unwrap();
+
+		drop(server);
+		sleep(Duration::from_millis(20)).await;
+
+		assert!(connect_async(format!("ws://{addr}")).await.is_err());
+	}
+
+	#[derive(Debug, PartialEq, Deserialize)]
+	enum Action {
+		Add(u32),
+	}
+
+	#[derive(Serialize)]
+	struct Counter {
+		total: u32,
+	}
+
+	async fn connect_typed() -> (SocketServer<Counter, Action>, Connection<Counter, Action>, Client) {
+		let mut server = SocketServer::new(0).await.unwrap();
+		let (mut connection, client) = connect_to(&mut server, "/").await;
+		connection.next_event().await.unwrap();
+
+		(server, connection, client)
+	}
+
+	#[tokio::test]
+	async fn deserializes_events_and_serializes_models() {
+		let (_server, mut connection, mut client) = connect_typed().await;
+
+		client.send(Message::Text("event() {\"Add\": 3}".to_owned())).await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Update(action)) => assert_eq!(action, Action::Add(3)),
+			event => panic!("Expected an update event, got {event:?}"),
+		}
+
+		connection.send(&Counter { total: 3 }).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) {\"total\":3}");
+	}
+
+	#[tokio::test]
+	async fn closes_on_events_of_the_wrong_type() {
+		let (_server, mut connection, mut client) = connect_typed().await;
+
+		client.send(Message::Text("event() {\"Remove\": 3}".to_owned())).await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Unsupported, Initiator::Server));
+		assert!(next_close_reason(&mut client)
+			.await
+			.starts_with("Received an 'event' body that doesn't match the expected type: unknown variant `Remove`"));
+	}
+
+	#[tokio::test]
+	async fn timeout_closes_inactive_connections() {
+		let (_server, mut connection, mut client) = connect("/").await;
+		connection.next_event().await.unwrap();
+
+		let event = connection.next_event_with_timeout(Duration::from_millis(20)).await;
+		assert_eq!(disconnection(event), (CloseCode::Policy, Initiator::Server));
+
+		let frame = next_close_frame(&mut client).await;
+		assert_eq!(frame.code, CloseCode::Policy);
+		assert_eq!(frame.reason, "Connection closed due to inactivity. When another operation is necessary, reconnect");
+	}
 }
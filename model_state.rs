@@ -0,0 +1,164 @@
+use crate::{SendError, SocketMessage, SocketMessageBuilder};
+use error_stack::ResultExt;
+use json_patch::{diff, Patch};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// How many patches are kept so that a client which missed some can catch up without a full snapshot
+const PATCH_HISTORY: usize = 32;
+
+/// A model along with its version and the patches which led up to it. The model is kept as a `Value`, as the next one is
+/// diffed against it and `sync` can be answered with it, so models are serialized twice: into a `Value`, then to text
+#[derive(Default)]
+pub(crate) struct ModelState {
+	value: Option<Value>,
+	version: u64,
+	history: VecDeque<Patch>,
+}
+
+impl ModelState {
+	pub fn version(&self) -> u64 {
+		self.version
+	}
+
+	/// Replace the model, bumping the version and recording the patch from the previous model
+	pub fn update(&mut self, value: Value) {
+		self.version += 1;
+
+		match &self.value {
+			Some(previous) => {
+				if self.history.len() == PATCH_HISTORY {
+					self.history.pop_front();
+				}
+
+				self.history.push_back(diff(previous, &value));
+			}
+			None => self.history.clear(),
+		}
+
+		self.value = Some(value);
+	}
+
+	/// The patch which produced the current version, if the previous version had a model
+	pub fn last_patch(&self) -> Option<&Patch> {
+		self.patches_since(self.version.saturating_sub(1))
+			.and_then(|mut patches| patches.next())
+			.map(|(_, patch)| patch)
+	}
+
+	/// The patches, along with the version each produces, which bring a client that has seen `version` up to date.
+	/// Returns `None` if some of them are no longer kept, in which case a snapshot must be sent instead
+	pub fn patches_since(&self, version: u64) -> Option<impl Iterator<Item = (u64, &Patch)>> {
+		let missed = usize::try_from(self.version.checked_sub(version)?).ok()?;
+		let skipped = self.history.len().checked_sub(missed)?;
+		let first_version = self.version - missed as u64 + 1;
+
+		Some(
+			self.history
+				.iter()
+				.skip(skipped)
+				.enumerate()
+				.map(move |(index, patch)| (first_version + index as u64, patch)),
+		)
+	}
+
+	/// The message which brings a client at the previous version up to date. Once the client has a model, only the
+	/// difference is sent, unless the snapshot turns out to be smaller
+	pub fn update_message(&self, pin: Option<Uuid>) -> error_stack::Result<SocketMessage, SendError> {
+		let snapshot = model_message("model", self.version, pin, self.value.as_ref())?;
+
+		let patch = match self.last_patch() {
+			Some(patch) => patch,
+			None => return Ok(snapshot),
+		};
+
+		let patch = model_message("patch", self.version, pin, Some(patch))?;
+
+		if patch.get_str().len() < snapshot.get_str().len() {
+			Ok(patch)
+		} else {
+			Ok(snapshot)
+		}
+	}
+
+	/// The messages which bring a client up to date. Clients that say which version they have seen are only sent the
+	/// patches they missed, as long as those are still available
+	pub fn sync_messages(&self, since: Option<u64>) -> error_stack::Result<Vec<SocketMessage>, SendError> {
+		match since.and_then(|version| self.patches_since(version)) {
+			Some(patches) => patches.map(|(version, patch)| model_message("patch", version, None, Some(patch))).collect(),
+			None => self.value.iter().map(|value| model_message("model", self.version, None, Some(value))).collect(),
+		}
+	}
+}
+
+/// Model messages carry the model version as context, followed by the pin of the event they respond to, if any
+fn model_message<T: Serialize>(
+	prefix: &str,
+	version: u64,
+	pin: Option<Uuid>,
+	body: Option<&T>,
+) -> error_stack::Result<SocketMessage, SendError> {
+	let context = match pin {
+		Some(pin) => format!("{version}:{pin}"),
+		None => version.to_string(),
+	};
+
+	let mut builder = SocketMessageBuilder::new(prefix).context(context);
+
+	if let Some(body) = body {
+		builder = builder.body(body);
+	}
+
+	builder.build().change_context(SendError::Serialize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+
+	fn versions_since(state: &ModelState, version: u64) -> Option<Vec<u64>> {
+		state
+			.patches_since(version)
+			.map(|patches| patches.map(|(version, _)| version).collect())
+	}
+
+	#[test]
+	fn tracks_versions_and_patches() {
+		let mut state = ModelState::default();
+		assert_eq!(state.version(), 0);
+		assert!(state.last_patch().is_none());
+
+		state.update(json!({ "count": 0 }));
+		assert_eq!(state.version(), 1);
+		assert!(state.last_patch().is_none());
+		assert_eq!(versions_since(&state, 0), None);
+
+		state.update(json!({ "count": 1 }));
+		state.update(json!({ "count": 2 }));
+		assert_eq!(state.value, Some(json!({ "count": 2 })));
+		assert_eq!(serde_json::to_value(state.last_patch().unwrap()).unwrap(), json!([{ "op": "replace", "path": "/count", "value": 2 }]));
+
+		assert_eq!(versions_since(&state, 1), Some(vec![2, 3]));
+		assert_eq!(versions_since(&state, 2), Some(vec![3]));
+		assert_eq!(versions_since(&state, 3), Some(vec![]));
+		assert_eq!(versions_since(&state, 4), None);
+	}
+
+	#[test]
+	fn forgets_old_patches() {
+		let mut state = ModelState::default();
+
+		for count in 0..PATCH_HISTORY + 5 {
+			state.update(json!(count));
+		}
+
+		let oldest = state.version() - PATCH_HISTORY as u64;
+
+		assert_eq!(versions_since(&state, oldest).map(|versions| versions.len()), Some(PATCH_HISTORY));
+		assert_eq!(versions_since(&state, oldest - 1), None);
+	}
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+/// Which `Origin` an upgrade request may come from, to protect cookie authenticated channels from cross-site WebSocket
+/// hijacking. Requests without an `Origin` header don't come from browsers, so they are always allowed
+#[derive(Clone)]
+pub struct OriginPolicy(Policy);
+
+#[derive(Clone)]
+enum Policy {
+	Any,
+	List(Vec<String>),
+	Predicate(Arc<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl Default for OriginPolicy {
+	fn default() -> Self {
+		OriginPolicy::any()
+	}
+}
+
+impl OriginPolicy {
+	/// Allow every origin. The default
+	pub fn any() -> OriginPolicy {
+		OriginPolicy(Policy::Any)
+	}
+
+	/// Allow origins such as `https://example.com`, which must match exactly, ignoring case. A pattern such as
+	/// `https://*.example.com` also allows every subdomain of `example.com` with the same scheme and port, but not
+	/// `example.com` itself. A `*` which isn't followed by a dot only matches itself
+	pub fn list<I: IntoIterator<Item = S>, S: Into<String>>(origins: I) -> OriginPolicy {
+		OriginPolicy(Policy::List(origins.into_iter().map(|origin| origin.into().to_ascii_lowercase()).collect()))
+	}
+
+	/// Allow the origins for which `predicate` returns true
+	pub fn predicate<F: Fn(&str) -> bool + Send + Sync + 'static>(predicate: F) -> OriginPolicy {
+		OriginPolicy(Policy::Predicate(Arc::new(predicate)))
+	}
+
+	pub(crate) fn allows(&self, origin: &str) -> bool {
+		match &self.0 {
+			Policy::Any => true,
+			Policy::List(patterns) => {
+				let origin = origin.to_ascii_lowercase();
+
+				patterns.iter().any(|pattern| matches_pattern(pattern, &origin))
+			}
+			Policy::Predicate(predicate) => predicate(origin),
+		}
+	}
+}
+
+fn matches_pattern(pattern: &str, origin: &str) -> bool {
+	let Some((scheme, host)) = pattern.split_once("://") else {
+		return pattern == origin;
+	};
+
+	// Only a whole leading label can be a wildcard, or `https://*example.com` would allow `https://evilexample.com`
+	let Some(suffix) = host.strip_prefix('*').filter(|suffix| suffix.starts_with('.')) else {
+		return pattern == origin;
+	};
+
+	let subdomain = origin
+		.strip_prefix(scheme)
+		.and_then(|rest| rest.strip_prefix("://"))
+		.and_then(|rest| rest.strip_suffix(suffix));
+
+	let Some(subdomain) = subdomain else {
+		return false;
+	};
+
+	// The suffix starts with a dot, so anything left over is one or more labels of the subdomain, unless it smuggles in
+	// a port, path or user info
+	!subdomain.is_empty() && !subdomain.contains([':', '/', '@'])
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		tests::{connect_to, raw_request},
+		SocketServer,
+	};
+	use hyper::header::{HeaderValue, ORIGIN};
+	use tokio_tungstenite::{connect_async, tungstenite::client::IntoClientRequest};
+
+	#[test]
+	fn matches_exact_and_wildcard_origins() {
+		let policy = OriginPolicy::list(["https://Example.com", "https://*.example.com", "http://*.local:8080"]);
+
+		assert!(policy.allows("https://example.com"));
+		assert!(policy.allows("HTTPS://EXAMPLE.COM"));
+		assert!(policy.allows("https://app.example.com"));
+		assert!(policy.allows("https://a.b.example.com"));
+		assert!(policy.allows("http://dev.local:8080"));
+
+		assert!(!policy.allows("http://example.com"));
+		assert!(!policy.allows("https://example.com:8443"));
+		assert!(!policy.allows("https://evilexample.com"));
+		assert!(!policy.allows("https://.example.com"));
+		assert!(!policy.allows("https://evil.com:.example.com"));
+		assert!(!policy.allows("http://dev.local"));
+		assert!(!policy.allows("null"));
+
+		let policy = OriginPolicy::list(["https://*example.com"]);
+		assert!(!policy.allows("https://evilexample.com"));
+		assert!(!policy.allows("https://example.com"));
+
+		let policy = OriginPolicy::predicate(|origin| origin.ends_with(".test"));
+		assert!(policy.allows("https://a.test"));
+		assert!(!policy.allows("https://a.com"));
+	}
+
+	#[tokio::test]
+	async fn rejects_mismatching_origins() {
+		let mut server: SocketServer = SocketServer::builder()
+			.origin_policy(OriginPolicy::list(["https://example.com"]))
+			.build()
+			.await
+			.unwrap();
+
+		let addr = server.local_addr().unwrap();
+
+		let response = raw_request(
+			addr,
+			"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nOrigin: https://evil.com\r\n\r\n",
+		)
+		.await;
+		assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+
+		let mut allowed = format!("ws://{addr}/").into_client_request().unwrap();
+		allowed.headers_mut().insert(ORIGIN, HeaderValue::from_static("https://example.com"));
+		let (_client, _) = connect_async(allowed).await.unwrap();
+		server.accept_connection().await.unwrap();
+
+		// Clients which aren't browsers don't send an origin at all
+		let (_connection, _client) = connect_to(&mut server, "/").await;
+	}
+}
@@ -0,0 +1,195 @@
+use crate::{
+	client_disconnected,
+	heartbeat::{next_beat, Beat, HeartbeatState},
+	idle, is_activity, next_tick, parse_client_message, server_disconnected, ticker,
+	writer::{Closed, Socket},
+	ClientMessage, CloseReason, ConnectionDetails, Event, Heartbeat, Signal, HEARTBEAT_REASON, INACTIVITY_REASON,
+};
+use futures::{
+	future::pending,
+	stream::{SplitStream, StreamExt},
+};
+use serde::de::DeserializeOwned;
+use std::{marker::PhantomData, sync::Arc, time::Duration};
+use tokio::{
+	select,
+	sync::mpsc::UnboundedReceiver,
+	time::{Instant, Interval},
+};
+use tungstenite::Message;
+use uuid::Uuid;
+
+/// The reading half of a connection, shared by `Connection` and `EventReader`. Client messages are merged with ticks,
+/// the heartbeat and the idle timeout, and anything which has to be written is left to the owner as a `Step`
+pub(crate) struct Reader<E> {
+	connection_details: Option<Box<ConnectionDetails>>,
+	pub stream: SplitStream<Socket>,
+	ticker: Option<Interval>,
+	idle_timeout: Option<Duration>,
+	last_activity: Instant,
+	heartbeat: Option<HeartbeatState>,
+	/// Set once `Event::Disconnected` has been returned
+	disconnected: bool,
+	closed: Arc<Closed>,
+	event: PhantomData<fn() -> E>,
+}
+
+/// What the owner of a `Reader` has to do next
+pub(crate) enum Step<E> {
+	/// Return this event, or `None` once the connection has ended
+	Event(Option<Event<E>>),
+	/// Keep the client's pin for the next model, then return the event
+	Update(Option<Uuid>, E),
+	/// Have the writing half act on this signal
+	Signal(Signal),
+	/// Close the connection, then return `Reader::end`. With a deadline, the client is most likely gone, so writing
+	/// the close frame shouldn't be waited on for longer
+	Close(CloseReason, Option<Duration>),
+}
+
+impl<E: DeserializeOwned> Reader<E> {
+	pub fn new(
+		stream: SplitStream<Socket>,
+		connection_details: ConnectionDetails,
+		idle_timeout: Option<Duration>,
+		heartbeat: Option<Heartbeat>,
+		closed: Arc<Closed>,
+	) -> Reader<E> {
+		Reader {
+			connection_details: Some(Box::new(connection_details)),
+			stream,
+			ticker: None,
+			idle_timeout,
+			last_activity: Instant::now(),
+			heartbeat: heartbeat.map(HeartbeatState::new),
+			disconnected: false,
+			closed,
+			event: PhantomData,
+		}
+	}
+
+	pub fn set_tick_interval(&mut self, period: Option<Duration>) {
+		self.ticker = period.map(ticker);
+	}
+
+	pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) {
+		self.idle_timeout = timeout;
+	}
+
+	pub fn set_heartbeat(&mut self, heartbeat: Option<Heartbeat>) {
+		self.heartbeat = heartbeat.map(HeartbeatState::new);
+	}
+
+	pub fn latency(&self) -> Option<Duration> {
+		self.heartbeat.as_ref().and_then(HeartbeatState::latency)
+	}
+
+	/// Wait for the next step, merging client messages with `signals`, ticks, the heartbeat and the idle timeout
+	pub async fn next(&mut self, mut signals: Option<&mut UnboundedReceiver<Signal>>) -> Step<E> {
+		if let Some(step) = self.settled() {
+			return step;
+		}
+
+		loop {
+			// Every branch here is cancel-safe, so nothing is lost when another branch wins
+			let message = select! {
+				message = self.stream.next() => message,
+				Some(signal) = next_signal(&mut signals) => return Step::Signal(signal),
+				_ = self.closed.written() => return Step::Event(self.closed_by_server()),
+				_ = next_tick(&mut self.ticker) => return Step::Event(Some(Event::Tick)),
+				beat = next_beat(&mut self.heartbeat) => match beat {
+					Beat::Ping(payload) => return Step::Signal(Signal::Ping(payload)),
+					Beat::Dead => {
+						let deadline = self.heartbeat.as_ref().map_or(Duration::ZERO, HeartbeatState::timeout);
+
+						return Step::Close(CloseReason::Policy(HEARTBEAT_REASON.to_owned()), Some(deadline));
+					}
+				},
+				_ = idle(self.idle_timeout, self.last_activity) => {
+					return Step::Close(CloseReason::Policy(INACTIVITY_REASON.to_owned()), None);
+				}
+			};
+
+			if let Some(step) = self.handle_message(message) {
+				return step;
+			}
+		}
+	}
+
+	/// Like `next`, but only for messages from the client
+	pub async fn next_socket(&mut self) -> Step<E> {
+		if let Some(step) = self.settled() {
+			return step;
+		}
+
+		loop {
+			let message = self.stream.next().await;
+
+			if let Some(step) = self.handle_message(message) {
+				return step;
+			}
+		}
+	}
+
+	/// Report the connection as disconnected by the server, once the owner has closed it with `reason`
+	pub fn end(&mut self, reason: &CloseReason) -> Event<E> {
+		self.disconnected = true;
+
+		server_disconnected(reason)
+	}
+
+	/// The step to take without reading, when the connection has just been accepted or has ended
+	fn settled(&mut self) -> Option<Step<E>> {
+		if let Some(details) = self.connection_details.take() {
+			return Some(Step::Event(Some(Event::Connect(details))));
+		}
+
+		if self.disconnected {
+			return Some(Step::Event(None));
+		}
+
+		self.closed_by_server().map(|event| Step::Event(Some(event)))
+	}
+
+	/// The `Event::Disconnected` to return once the server has closed the connection
+	fn closed_by_server(&mut self) -> Option<Event<E>> {
+		let reason = self.closed.reason()?;
+
+		self.disconnected = true;
+
+		Some(server_disconnected(reason))
+	}
+
+	fn handle_message(&mut self, message: Option<Result<Message, tungstenite::Error>>) -> Option<Step<E>> {
+		if is_activity(&message) {
+			self.last_activity = Instant::now();
+		}
+
+		match parse_client_message(message) {
+			ClientMessage::Ignore => None,
+			ClientMessage::Closed(code, reason) => {
+				self.disconnected = true;
+
+				Some(Step::Event(Some(client_disconnected(self.closed.reason(), code, reason))))
+			}
+			ClientMessage::Pong(payload) => {
+				if let Some(heartbeat) = &mut self.heartbeat {
+					heartbeat.pong(&payload);
+				}
+
+				None
+			}
+			ClientMessage::Sync(since) => Some(Step::Signal(Signal::Sync(since))),
+			ClientMessage::Event(pin, event) => Some(Step::Update(pin, event)),
+			ClientMessage::Invalid(reason) => Some(Step::Close(reason, None)),
+		}
+	}
+}
+
+/// The next signal of an optional receiver, which never comes without one
+async fn next_signal(signals: &mut Option<&mut UnboundedReceiver<Signal>>) -> Option<Signal> {
+	match signals {
+		Some(signals) => signals.recv().await,
+		None => pending().await,
+	}
+}
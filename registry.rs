@@ -0,0 +1,81 @@
+use crate::{CloseReason, Signal};
+use std::{
+	collections::HashMap,
+	sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+
+struct RegistryState {
+	connections: HashMap<u64, UnboundedSender<Signal>>,
+	next_id: u64,
+	drain: Option<Sender<()>>,
+	closing: Option<CloseReason>,
+}
+
+/// The live connections of a server, so that they can all be closed on shutdown. Every registration holds a clone of
+/// the drain sender, so the matching receiver resolves once all of them have been dropped
+#[derive(Clone)]
+pub(crate) struct Registry {
+	state: Arc<Mutex<RegistryState>>,
+}
+
+impl Registry {
+	pub fn new(drain: Sender<()>) -> Registry {
+		Registry {
+			state: Arc::new(Mutex::new(RegistryState {
+				connections: HashMap::new(),
+				next_id: 0,
+				drain: Some(drain),
+				closing: None,
+			})),
+		}
+	}
+
+	pub fn register(&self, signals: UnboundedSender<Signal>) -> Registration {
+		let mut state = self.state.lock().unwrap();
+		let id = state.next_id;
+
+		state.next_id += 1;
+
+		// Connections that are still being set up during shutdown are closed as soon as they are registered
+		if let Some(reason) = &state.closing {
+			let _ = signals.send(Signal::Close(reason.clone()));
+		}
+
+		state.connections.insert(id, signals);
+
+		Registration {
+			registry: self.clone(),
+			id,
+			_drain: state.drain.clone(),
+		}
+	}
+
+	/// Ask every live connection to close, and stop handing out drain senders
+	pub fn close_all(&self, reason: CloseReason) {
+		let mut state = self.state.lock().unwrap();
+
+		state.drain.take();
+
+		for signals in state.connections.values() {
+			let _ = signals.send(Signal::Close(reason.clone()));
+		}
+
+		state.closing = Some(reason);
+	}
+}
+
+/// A connection's entry in a `Registry`, removed when this is dropped
+pub(crate) struct Registration {
+	registry: Registry,
+	id: u64,
+	_drain: Option<Sender<()>>,
+}
+
+impl Drop for Registration {
+	fn drop(&mut self) {
+		if let Ok(mut state) = self.registry.state.lock() {
+			state.connections.remove(&self.id);
+		}
+	}
+}
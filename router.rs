@@ -0,0 +1,120 @@
+use crate::Connection;
+use futures::future::BoxFuture;
+use percent_encoding::percent_decode_str;
+use std::{collections::HashMap, sync::Arc};
+
+pub(crate) type Handler<M, E> = Arc<dyn Fn(Connection<M, E>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+enum Segment {
+	Literal(String),
+	Param(String),
+}
+
+struct Route<M, E> {
+	segments: Vec<Segment>,
+	handler: Handler<M, E>,
+}
+
+/// Finds the handler for a path, out of patterns such as `/docs/:id`
+pub(crate) struct Router<M, E> {
+	routes: Arc<Vec<Route<M, E>>>,
+}
+
+impl<M, E> Clone for Router<M, E> {
+	fn clone(&self) -> Self {
+		Router {
+			routes: self.routes.clone(),
+		}
+	}
+}
+
+impl<M, E> Default for Router<M, E> {
+	fn default() -> Self {
+		Router {
+			routes: Arc::new(Vec::new()),
+		}
+	}
+}
+
+impl<M, E> Router<M, E> {
+	/// Only called while building the server, before the routes are shared
+	pub fn add(&mut self, pattern: &str, handler: Handler<M, E>) {
+		let segments = split(pattern)
+			.map(|segment| match segment.strip_prefix(':') {
+				Some(name) => Segment::Param(name.to_owned()),
+				None => Segment::Literal(segment.to_owned()),
+			})
+			.collect();
+
+		Arc::get_mut(&mut self.routes)
+			.expect("Routes are only added before the server is built")
+			.push(Route { segments, handler });
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.routes.is_empty()
+	}
+
+	/// The handler of the first route matching `path`, along with its captured, percent-decoded params
+	pub fn find(&self, path: &str) -> Option<(Handler<M, E>, HashMap<String, String>)> {
+		let segments = split(path).collect::<Vec<_>>();
+
+		'routes: for route in self.routes.iter() {
+			if route.segments.len() != segments.len() {
+				continue;
+			}
+
+			let mut params = HashMap::new();
+
+			for (pattern, segment) in route.segments.iter().zip(&segments) {
+				match pattern {
+					Segment::Literal(literal) if literal == segment => {}
+					Segment::Param(name) if !segment.is_empty() => {
+						params.insert(name.clone(), percent_decode_str(segment).decode_utf8_lossy().into_owned());
+					}
+					_ => continue 'routes,
+				}
+			}
+
+			return Some((route.handler.clone(), params));
+		}
+
+		None
+	}
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+	path.strip_prefix('/').unwrap_or(path).split('/')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use futures::FutureExt;
+	use pretty_assertions::assert_eq;
+	use serde_json::Value;
+
+	fn handler() -> Handler<Value, Value> {
+		Arc::new(|_| async {}.boxed())
+	}
+
+	#[test]
+	fn matches_literals_and_params() {
+		let mut router = Router::default();
+		router.add("/", handler());
+		router.add("/docs/:id", handler());
+		router.add("/docs/:id/comments/:comment", handler());
+
+		assert_eq!(router.find("/").unwrap().1, HashMap::new());
+		assert_eq!(router.find("/docs/a%20b").unwrap().1, HashMap::from([("id".to_owned(), "a b".to_owned())]));
+		assert_eq!(
+			router.find("/docs/4/comments/2").unwrap().1,
+			HashMap::from([("id".to_owned(), "4".to_owned()), ("comment".to_owned(), "2".to_owned())])
+		);
+
+		assert!(router.find("/docs").is_none());
+		assert!(router.find("/docs/").is_none());
+		assert!(router.find("/docs/4/comments").is_none());
+		assert!(router.find("/other").is_none());
+	}
+}
@@ -0,0 +1,479 @@
+use crate::{router::Router, Connection, HandshakeRequest, Heartbeat, OriginPolicy, SocketServer};
+use error_stack::ResultExt;
+use futures::{future::BoxFuture, FutureExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt};
+use hyper::{
+	body::{Body, Bytes, Incoming},
+	Request, Response, StatusCode,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+#[cfg(feature = "tls")]
+use crate::tls::TlsSource;
+#[cfg(feature = "tls")]
+use std::path::PathBuf;
+use std::{any::Any, error::Error as StdError, future::Future, net::SocketAddr, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::net::TcpListener;
+#[cfg(feature = "tls")]
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+#[derive(Debug, Error)]
+pub enum SocketServerError {
+	#[error("Failed to bind to {0}")]
+	Bind(SocketAddr),
+
+	#[error("Failed to get the local address of the listener")]
+	LocalAddr,
+
+	#[error("The connection queue size must be greater than zero")]
+	InvalidQueueSize,
+
+	#[cfg(feature = "tls")]
+	#[error("Failed to read TLS certificates from {0}")]
+	TlsCertificates(PathBuf),
+
+	#[cfg(feature = "tls")]
+	#[error("Failed to read a TLS private key from {0}")]
+	TlsKey(PathBuf),
+
+	#[cfg(feature = "tls")]
+	#[error("The TLS certificates and private key were rejected")]
+	TlsConfig,
+}
+
+type Result<T> = error_stack::Result<T, SocketServerError>;
+
+enum Listen {
+	Addr(SocketAddr),
+	Listener(TcpListener),
+}
+
+/// The body of every response the server sends, so that fallback responses can be streamed
+pub(crate) type ResponseBody = UnsyncBoxBody<Bytes, Box<dyn StdError + Send + Sync>>;
+
+pub(crate) type Fallback = Arc<dyn Fn(Request<Incoming>) -> BoxFuture<'static, Response<ResponseBody>> + Send + Sync>;
+
+pub(crate) type Authenticate = Arc<
+	dyn Fn(HandshakeRequest) -> BoxFuture<'static, std::result::Result<Arc<dyn Any + Send + Sync>, StatusCode>> + Send + Sync,
+>;
+
+/// Options which apply once the server is running
+pub(crate) struct ServerOptions<M, E> {
+	pub queue_size: usize,
+	pub router: Router<M, E>,
+	pub idle_timeout: Option<Duration>,
+	pub heartbeat: Option<Heartbeat>,
+	pub fallback: Option<Fallback>,
+	pub origin_policy: OriginPolicy,
+	pub authenticate: Option<Authenticate>,
+	#[cfg(feature = "tls")]
+	pub tls: Option<TlsAcceptor>,
+	#[cfg(feature = "tls")]
+	pub tls_handshake_timeout: Duration,
+}
+
+pub struct SocketServerBuilder<M = Value, E = Value> {
+	listen: Listen,
+	options: ServerOptions<M, E>,
+	#[cfg(feature = "tls")]
+	tls: Option<TlsSource>,
+}
+
+impl<M, E> Default for SocketServerBuilder<M, E> {
+	fn default() -> Self {
+		SocketServerBuilder {
+			listen: Listen::Addr(SocketAddr::from(([127, 0, 0, 1], 0))),
+			options: ServerOptions {
+				queue_size: 100,
+				router: Router::default(),
+				idle_timeout: None,
+				heartbeat: None,
+				fallback: None,
+				origin_policy: OriginPolicy::any(),
+				authenticate: None,
+				#[cfg(feature = "tls")]
+				tls: None,
+				#[cfg(feature = "tls")]
+				tls_handshake_timeout: Duration::from_secs(10),
+			},
+			#[cfg(feature = "tls")]
+			tls: None,
+		}
+	}
+}
+
+impl<M: Serialize + 'static, E: DeserializeOwned + 'static> SocketServerBuilder<M, E> {
+	/// A builder which listens on a random port of 127.0.0.1 unless told otherwise
+	pub fn new() -> SocketServerBuilder<M, E> {
+		SocketServerBuilder::default()
+	}
+
+	/// Listen on any address, such as `0.0.0.0:8080` or `[::1]:8080`
+	pub fn bind<A: Into<SocketAddr>>(mut self, addr: A) -> SocketServerBuilder<M, E> {
+		self.listen = Listen::Addr(addr.into());
+
+		self
+	}
+
+	/// Accept connections from a listener which is already bound
+	pub fn listener(mut self, listener: TcpListener) -> SocketServerBuilder<M, E> {
+		self.listen = Listen::Listener(listener);
+
+		self
+	}
+
+	/// How many connections can wait to be accepted with `SocketServer::accept_connection`. Defaults to 100
+	pub fn queue_size(mut self, queue_size: usize) -> SocketServerBuilder<M, E> {
+		self.options.queue_size = queue_size;
+
+		self
+	}
+
+	/// Close connections when the client sends nothing for this long. Can be changed per connection with
+	/// `Connection::set_idle_timeout`. Defaults to no timeout
+	pub fn idle_timeout(mut self, idle_timeout: Duration) -> SocketServerBuilder<M, E> {
+		self.options.idle_timeout = Some(idle_timeout);
+
+		self
+	}
+
+	/// Handle requests that aren't WebSocket upgrades, such as health checks or static assets, on the same port. Without
+	/// a fallback, they are answered with 426 Upgrade Required. Any body can be returned, so large files can be streamed
+	/// rather than read into memory first
+	pub fn fallback<F, Fut, B>(mut self, fallback: F) -> SocketServerBuilder<M, E>
+	where
+		F: Fn(Request<Incoming>) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = Response<B>> + Send + 'static,
+		B: Body<Data = Bytes> + Send + 'static,
+		B::Error: Into<Box<dyn StdError + Send + Sync>>,
+	{
+		self.options.fallback = Some(Arc::new(move |request| {
+			fallback(request).map(|response| response.map(|body| body.map_err(Into::into).boxed_unsync())).boxed()
+		}));
+
+		self
+	}
+
+	/// Ping clients every `interval`, and close connections which don't answer within `timeout` with
+	/// `Event::Disconnected`. Can be changed per connection with `Connection::set_heartbeat`. Defaults to no pings
+	pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> SocketServerBuilder<M, E> {
+		self.options.heartbeat = Some(Heartbeat { interval, timeout });
+
+		self
+	}
+
+	/// Give connections to paths matching `pattern`, such as `/docs/:id`, to a task running `handler` instead of the
+	/// queue consumed by `SocketServer::accept_connection`. Segments starting with `:` are captured in
+	/// `ConnectionDetails::path_params`, and the first matching route wins. Once a route is added, upgrades to paths
+	/// without one are answered with 404 Not Found
+	pub fn route<F, Fut>(mut self, pattern: &str, handler: F) -> SocketServerBuilder<M, E>
+	where
+		F: Fn(Connection<M, E>) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = ()> + Send + 'static,
+	{
+		self.options.router.add(pattern, Arc::new(move |connection| handler(connection).boxed()));
+
+		self
+	}
+
+	/// Reject upgrade requests from origins which `policy` doesn't allow with 403 Forbidden. Allows every origin by
+	/// default
+	pub fn origin_policy(mut self, policy: OriginPolicy) -> SocketServerBuilder<M, E> {
+		self.options.origin_policy = policy;
+
+		self
+	}
+
+	/// Check upgrade requests before they are answered. An error status rejects the request, so the client never
+	/// becomes a `Connection`. The identity returned otherwise is available through `ConnectionDetails::identity`
+	pub fn authenticate<F, Fut, I>(mut self, authenticate: F) -> SocketServerBuilder<M, E>
+	where
+		F: Fn(HandshakeRequest) -> Fut + Send + Sync + 'static,
+		Fut: Future<Output = std::result::Result<I, StatusCode>> + Send + 'static,
+		I: Send + Sync + 'static,
+	{
+		self.options.authenticate = Some(Arc::new(move |request| {
+			let identity = authenticate(request);
+
+			async move { Ok(Arc::new(identity.await?) as Arc<dyn Any + Send + Sync>) }.boxed()
+		}));
+
+		self
+	}
+
+	/// Terminate TLS with a PEM certificate chain and private key, read when the server is built, so that clients
+	/// connect with `wss://`
+	#[cfg(feature = "tls")]
+	pub fn tls_pem<C: Into<PathBuf>, K: Into<PathBuf>>(mut self, certificates: C, key: K) -> SocketServerBuilder<M, E> {
+		self.tls = Some(TlsSource::Pem {
+			certificates: certificates.into(),
+			key: key.into(),
+		});
+
+		self
+	}
+
+	/// Terminate TLS with an existing rustls configuration, so that clients connect with `wss://`
+	#[cfg(feature = "tls")]
+	pub fn tls_config(mut self, config: Arc<ServerConfig>) -> SocketServerBuilder<M, E> {
+		self.tls = Some(TlsSource::Config(config));
+
+		self
+	}
+
+	/// Drop TCP connections which haven't finished the TLS handshake after this long, so that clients which connect and
+	/// then send nothing don't hold on to a task. Defaults to 10 seconds
+	#[cfg(feature = "tls")]
+	pub fn tls_handshake_timeout(mut self, timeout: Duration) -> SocketServerBuilder<M, E> {
+		self.options.tls_handshake_timeout = timeout;
+
+		self
+	}
+
+	#[cfg_attr(not(feature = "tls"), allow(unused_mut))]
+	pub async fn build(mut self) -> Result<SocketServer<M, E>> {
+		self.check_options()?;
+
+		#[cfg(feature = "tls")]
+		if let Some(tls) = self.tls {
+			self.options.tls = Some(tls.acceptor()?);
+		}
+
+		let listener = match self.listen {
+			Listen::Addr(addr) => TcpListener::bind(addr).await.change_context(SocketServerError::Bind(addr))?,
+			Listen::Listener(listener) => listener,
+		};
+
+		let local_addr = listener.local_addr().change_context(SocketServerError::LocalAddr)?;
+
+		Ok(SocketServer::serve(listener, local_addr, self.options))
+	}
+
+	/// Build a server without a port of its own, for mounting `SocketServer::service` inside another server. The
+	/// listen address, fallback and TLS options are ignored
+	pub fn build_unbound(self) -> Result<SocketServer<M, E>> {
+		self.check_options()?;
+
+		Ok(SocketServer::unbound(&self.options))
+	}
+
+	fn check_options(&self) -> Result<()> {
+		if self.options.queue_size == 0 {
+			Err(SocketServerError::InvalidQueueSize)?
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		tests::{connect_to, disconnection, next_close_reason, next_text, raw_request},
+		CloseCode, Event, Initiator,
+	};
+	use futures::stream;
+	use http_body_util::{Full, StreamBody};
+	use hyper::{
+		body::Frame,
+		header::{HeaderValue, COOKIE},
+	};
+	use std::convert::Infallible;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpStream,
+	};
+	use tokio_tungstenite::{
+		client_async, connect_async,
+		tungstenite::{client::IntoClientRequest, Error},
+	};
+
+	#[tokio::test]
+	async fn serves_an_existing_listener() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+
+		let mut server: SocketServer = SocketServer::builder().listener(listener).queue_size(1).build().await.unwrap();
+		assert_eq!(server.local_addr(), Some(addr));
+
+		let (mut connection, _client) = connect_to(&mut server, "/path").await;
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => assert_eq!(details.path, "/path"),
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn reports_invalid_options() {
+		let error = SocketServer::<Value, Value>::builder().queue_size(0).build().await.err().unwrap();
+		assert!(matches!(error.current_context(), SocketServerError::InvalidQueueSize));
+
+		let taken = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+		let _listener = TcpListener::bind(taken).await.unwrap();
+
+		let error = SocketServer::<Value, Value>::builder().bind(taken).build().await.err().unwrap();
+		assert!(matches!(error.current_context(), SocketServerError::Bind(addr) if *addr == taken));
+	}
+
+	#[tokio::test]
+	async fn keeps_connections_alive_for_an_upgrade_after_a_plain_request() {
+		let mut server: SocketServer = SocketServer::builder()
+			.fallback(|_| async move { Response::new(Full::from("ok")) })
+			.build()
+			.await
+			.unwrap();
+
+		let mut stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+		stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+
+		let mut response = Vec::new();
+
+		while !response.ends_with(b"\r\n\r\nok") {
+			let mut buffer = [0; 1024];
+			let read = stream.read(&mut buffer).await.unwrap();
+			assert_ne!(read, 0, "The server closed the connection after a plain request");
+
+			response.extend_from_slice(&buffer[..read]);
+		}
+
+		let (_client, _) = client_async("ws://localhost/", stream).await.unwrap();
+		assert!(matches!(server.accept_connection().await.unwrap().next_event().await, Some(Event::Connect(_))));
+	}
+
+	#[tokio::test]
+	async fn serves_plain_requests_with_the_fallback() {
+		let mut server: SocketServer = SocketServer::builder()
+			.fallback(|request| async move {
+				let mut response = Response::new(Full::from("ok"));
+
+				if request.uri().path() != "/healthz" {
+					*response.status_mut() = StatusCode::NOT_FOUND;
+				}
+
+				response
+			})
+			.build()
+			.await
+			.unwrap();
+
+		let addr = server.local_addr().unwrap();
+
+		let response = raw_request(addr, "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+		assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+		assert!(response.ends_with("\r\n\r\nok"));
+
+		let response = raw_request(addr, "GET /missing HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await;
+		assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+		let (mut connection, _client) = connect_to(&mut server, "/healthz").await;
+		assert!(matches!(connection.next_event().await, Some(Event::Connect(_))));
+	}
+
+	#[tokio::test]
+	async fn streams_fallback_responses() {
+		let server: SocketServer = SocketServer::builder()
+			.fallback(|_| async move {
+				let chunks = ["first", "second"].map(|chunk| Ok::<_, Infallible>(Frame::data(Bytes::from(chunk))));
+
+				Response::new(StreamBody::new(stream::iter(chunks)))
+			})
+			.build()
+			.await
+			.unwrap();
+
+		let request = "GET /large HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+		let response = raw_request(server.local_addr().unwrap(), request).await;
+
+		assert!(response.contains("transfer-encoding: chunked\r\n"));
+		assert!(response.ends_with("\r\n\r\n5\r\nfirst\r\n6\r\nsecond\r\n0\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn authenticates_upgrade_requests() {
+		#[derive(Debug, PartialEq)]
+		struct User(String);
+
+		let mut server: SocketServer = SocketServer::builder()
+			.authenticate(|request| async move {
+				match request.cookies.get("session").map(String::as_str) {
+					Some("secret") => Ok(User(request.query_params["name"].clone())),
+					_ => Err(StatusCode::UNAUTHORIZED),
+				}
+			})
+			.build()
+			.await
+			.unwrap();
+
+		let addr = server.local_addr().unwrap();
+
+		let response = raw_request(
+			addr,
+			"GET /?name=eve HTTP/1.1\r\nHost: localhost\r\nConnection: Upgrade, close\r\nUpgrade: websocket\r\n\
+			Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nCookie: session=wrong\r\n\r\n",
+		)
+		.await;
+		assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+		let mut request = format!("ws://{addr}/?name=ada").into_client_request().unwrap();
+		request.headers_mut().insert(COOKIE, HeaderValue::from_static("session=secret"));
+
+		let (_client, _) = connect_async(request).await.unwrap();
+		let mut connection = server.accept_connection().await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => {
+				assert_eq!(details.identity::<User>(), Some(&User("ada".to_owned())));
+				assert_eq!(details.identity::<String>(), None);
+			}
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn routes_connections_to_handlers() {
+		let server: SocketServer = SocketServer::builder()
+			.route("/docs/:id", |mut connection: Connection| async move {
+				if let Some(Event::Connect(details)) = connection.next_event().await {
+					connection.send(&json!(details.path_params["id"])).await.unwrap();
+				}
+
+				while connection.next_event().await.is_some() {}
+			})
+			.build()
+			.await
+			.unwrap();
+
+		let addr = server.local_addr().unwrap();
+
+		let (mut client, _) = connect_async(format!("ws://{addr}/docs/a%20b")).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) \"a b\"");
+
+		match connect_async(format!("ws://{addr}/users/1")).await {
+			Err(Error::Http(response)) => assert_eq!(response.status(), StatusCode::NOT_FOUND),
+			result => panic!("Expected a 404 response, got {result:?}"),
+		}
+	}
+
+	#[tokio::test]
+	async fn closes_idle_connections() {
+		let mut server: SocketServer = SocketServer::builder()
+			.idle_timeout(Duration::from_millis(20))
+			.build()
+			.await
+			.unwrap();
+
+		let (mut connection, mut client) = connect_to(&mut server, "/").await;
+		connection.next_event().await.unwrap();
+
+		assert_eq!(disconnection(connection.next_event().await), (CloseCode::Policy, Initiator::Server));
+		assert_eq!(
+			next_close_reason(&mut client).await,
+			"Connection closed due to inactivity. When another operation is necessary, reconnect"
+		);
+	}
+}
@@ -0,0 +1,95 @@
+use crate::acceptor::Acceptor;
+use futures::{future::BoxFuture, FutureExt};
+use http_body_util::Full;
+use hyper::{body::Bytes, Request, Response};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::{
+	convert::Infallible,
+	task::{Context, Poll},
+};
+use tower_service::Service;
+
+/// Upgrades requests into connections for `SocketServer::accept_connection`, so that the socket channel can be mounted
+/// at a route of another server, such as an axum `Router`, behind its middleware. Requests which aren't WebSocket
+/// upgrades are answered with 426 Upgrade Required
+pub struct SocketService<M = Value, E = Value> {
+	acceptor: Acceptor<M, E>,
+}
+
+impl<M, E> SocketService<M, E> {
+	pub(crate) fn new(acceptor: Acceptor<M, E>) -> SocketService<M, E> {
+		SocketService { acceptor }
+	}
+}
+
+impl<M, E> Clone for SocketService<M, E> {
+	fn clone(&self) -> Self {
+		SocketService {
+			acceptor: self.acceptor.clone(),
+		}
+	}
+}
+
+impl<M, E, B> Service<Request<B>> for SocketService<M, E>
+where
+	M: Serialize + 'static,
+	E: DeserializeOwned + 'static,
+	B: Send + 'static,
+{
+	type Response = Response<Full<Bytes>>;
+	type Error = Infallible;
+	type Future = BoxFuture<'static, Result<Response<Full<Bytes>>, Infallible>>;
+
+	fn poll_ready(&mut self, _context: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn call(&mut self, request: Request<B>) -> Self::Future {
+		let acceptor = self.acceptor.clone();
+
+		async move { Ok(acceptor.accept(request).await) }.boxed()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::{tests::next_text, Event, SocketServer};
+	use axum::{routing::get, Router};
+	use futures::SinkExt;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+	use tokio::net::TcpListener;
+	use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+	#[tokio::test]
+	async fn mounts_inside_an_axum_router() {
+		let mut server: SocketServer = SocketServer::builder().build_unbound().unwrap();
+		assert_eq!(server.local_addr(), None);
+
+		let router = Router::new()
+			.route("/healthz", get(|| async { "ok" }))
+			.route_service("/socket", server.service());
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move { axum::serve(listener, router).await });
+
+		let (mut client, _) = connect_async(format!("ws://{addr}/socket?room=1")).await.unwrap();
+		let mut connection = server.accept_connection().await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => {
+				assert_eq!(details.path, "/socket");
+				assert_eq!(details.query_params.get("room").map(String::as_str), Some("1"));
+			}
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+
+		client.send(Message::Text("event() \"ping\"".to_owned())).await.unwrap();
+		assert!(matches!(connection.next_event().await, Some(Event::Update(value)) if value == json!("ping")));
+
+		connection.send(&json!([1])).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) [1]");
+	}
+}
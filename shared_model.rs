@@ -0,0 +1,470 @@
+use crate::{model_state::ModelState, Connection, ConnectionDetails, SendError, Signal, SocketMessage};
+use error_stack::ResultExt;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{to_value, Value};
+use std::{
+	collections::HashMap,
+	marker::PhantomData,
+	sync::{Arc, Mutex, Weak},
+};
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+/// Called with the state of a shared model once its last member has left
+type OnEmpty = Arc<dyn Fn(&Arc<Mutex<SharedState>>) + Send + Sync>;
+
+#[derive(Default)]
+struct SharedState {
+	model: ModelState,
+	members: HashMap<u64, UnboundedSender<Signal>>,
+	next_member_id: u64,
+	on_empty: Option<OnEmpty>,
+}
+
+impl SharedState {
+	/// Apply an update and send it to every member except `origin`. Returns the message for `origin`, which carries its pin
+	fn update(&mut self, value: Value, origin: Option<(u64, Option<Uuid>)>) -> error_stack::Result<String, SendError> {
+		self.model.update(value);
+
+		let message = self.model.update_message(None)?.to_string();
+		let origin_id = origin.map(|(id, _)| id);
+
+		self.members
+			.retain(|id, signals| Some(*id) == origin_id || signals.send(Signal::Message(message.clone())).is_ok());
+
+		match origin {
+			Some((_, Some(pin))) => Ok(self.model.update_message(Some(pin))?.to_string()),
+			_ => Ok(message),
+		}
+	}
+}
+
+/// A model shared by many connections. Updating it once sends the update, as a snapshot or a patch, to every member.
+/// Versions are kept per shared model, so clients can catch up with `sync` even after reconnecting
+pub struct SharedModel<M = Value> {
+	state: Arc<Mutex<SharedState>>,
+	model: PhantomData<fn(&M)>,
+}
+
+impl<M> Clone for SharedModel<M> {
+	fn clone(&self) -> Self {
+		SharedModel {
+			state: self.state.clone(),
+			model: PhantomData,
+		}
+	}
+}
+
+impl<M> Default for SharedModel<M> {
+	fn default() -> Self {
+		SharedModel {
+			state: Default::default(),
+			model: PhantomData,
+		}
+	}
+}
+
+impl<M: Serialize> SharedModel<M> {
+	pub fn new() -> SharedModel<M> {
+		SharedModel::default()
+	}
+
+	/// Add a connection to this model, sending it the current model if there is one. From then on, `Connection::send`
+	/// updates this model for every member, attaching the connection's pin only to its own copy
+	pub async fn join<E: DeserializeOwned>(&self, connection: &mut Connection<M, E>) -> error_stack::Result<(), SendError> {
+		// Leaving the previous model takes its locks, so it has to happen before any are held
+		connection.writer.shared = None;
+
+		let messages = self.add_member(connection)?;
+
+		send_messages(connection, messages).await
+	}
+
+	/// Make a connection a member, returning the messages which bring it up to date
+	fn add_member<E: DeserializeOwned>(
+		&self,
+		connection: &mut Connection<M, E>,
+	) -> error_stack::Result<Vec<SocketMessage>, SendError> {
+		let mut state = self.state.lock().unwrap();
+		let id = state.next_member_id;
+
+		state.next_member_id += 1;
+		state.members.insert(id, connection.signals_sender.clone());
+
+		connection.writer.shared = Some(Membership {
+			state: self.state.clone(),
+			id,
+		});
+
+		state.model.sync_messages(None)
+	}
+
+	/// Update the model for every member
+	pub fn update(&self, model: &M) -> error_stack::Result<(), SendError> {
+		let model = to_value(model).change_context(SendError::Serialize)?;
+
+		self.state.lock().unwrap().update(model, None)?;
+
+		Ok(())
+	}
+
+	pub fn version(&self) -> u64 {
+		self.state.lock().unwrap().model.version()
+	}
+
+	pub fn member_count(&self) -> usize {
+		self.state.lock().unwrap().members.len()
+	}
+}
+
+/// A connection's place in a `SharedModel`. The connection is removed from the model when this is dropped
+pub(crate) struct Membership {
+	state: Arc<Mutex<SharedState>>,
+	id: u64,
+}
+
+impl Membership {
+	pub fn update(&self, value: Value, pin: Option<Uuid>) -> error_stack::Result<String, SendError> {
+		self.state.lock().unwrap().update(value, Some((self.id, pin)))
+	}
+
+	pub fn sync_messages(&self, since: Option<u64>) -> error_stack::Result<Vec<String>, SendError> {
+		let messages = self.state.lock().unwrap().model.sync_messages(since)?;
+
+		Ok(messages.into_iter().map(SocketMessage::to_string).collect())
+	}
+}
+
+impl Drop for Membership {
+	fn drop(&mut self) {
+		let on_empty = match self.state.lock() {
+			Ok(mut state) => {
+				state.members.remove(&self.id);
+				state.on_empty.clone().filter(|_| state.members.is_empty())
+			}
+			Err(_) => None,
+		};
+
+		// Called without the lock, since it takes the lock of the `Rooms` first
+		if let Some(on_empty) = on_empty {
+			on_empty(&self.state);
+		}
+	}
+}
+
+/// How `Rooms` picks the room of a connection
+#[derive(Debug, Clone)]
+pub enum RoomKey {
+	Path,
+	QueryParam(String),
+}
+
+/// Shared models keyed by a part of `ConnectionDetails`, such as the path or a query parameter. A room is removed, along
+/// with its model, once the last of its members leaves. Rooms which were created with `Rooms::get` but never joined
+/// stay until they are removed with `Rooms::remove`
+pub struct Rooms<M = Value> {
+	key: RoomKey,
+	rooms: Arc<Mutex<HashMap<String, SharedModel<M>>>>,
+}
+
+impl<M> Clone for Rooms<M> {
+	fn clone(&self) -> Self {
+		Rooms {
+			key: self.key.clone(),
+			rooms: self.rooms.clone(),
+		}
+	}
+}
+
+impl<M: Serialize + 'static> Rooms<M> {
+	pub fn new(key: RoomKey) -> Rooms<M> {
+		Rooms {
+			key,
+			rooms: Default::default(),
+		}
+	}
+
+	pub fn by_path() -> Rooms<M> {
+		Rooms::new(RoomKey::Path)
+	}
+
+	pub fn by_query_param<S: Into<String>>(name: S) -> Rooms<M> {
+		Rooms::new(RoomKey::QueryParam(name.into()))
+	}
+
+	/// The name of the room that a connection with these details belongs in, if any
+	pub fn room_name<'a>(&self, details: &'a ConnectionDetails) -> Option<&'a str> {
+		match &self.key {
+			RoomKey::Path => Some(&details.path),
+			RoomKey::QueryParam(name) => details.query_params.get(name).map(String::as_str),
+		}
+	}
+
+	/// Get a room, creating it if it doesn't exist yet. A room which is created here and never joined isn't removed
+	/// automatically. Prefer `Rooms::join` for adding connections, as a room got here is removed once its last member
+	/// leaves, even if a connection is about to join it
+	pub fn get(&self, name: &str) -> SharedModel<M> {
+		self.get_locked(&mut self.rooms.lock().unwrap(), name)
+	}
+
+	fn get_locked(&self, rooms: &mut HashMap<String, SharedModel<M>>, name: &str) -> SharedModel<M> {
+		if let Some(room) = rooms.get(name) {
+			return room.clone();
+		}
+
+		let room = SharedModel::new();
+		room.state.lock().unwrap().on_empty = Some(remove_when_empty(Arc::downgrade(&self.rooms), name.to_owned()));
+		rooms.insert(name.to_owned(), room.clone());
+
+		room
+	}
+
+	/// Get a room if it exists, without creating it
+	pub fn find(&self, name: &str) -> Option<SharedModel<M>> {
+		self.rooms.lock().unwrap().get(name).cloned()
+	}
+
+	/// Remove a room. Its members stay connected to the returned model
+	pub fn remove(&self, name: &str) -> Option<SharedModel<M>> {
+		self.rooms.lock().unwrap().remove(name)
+	}
+
+	/// Add a connection to the room picked from its details. Returns `None` if the details don't name a room
+	pub async fn join<E: DeserializeOwned>(
+		&self,
+		connection: &mut Connection<M, E>,
+		details: &ConnectionDetails,
+	) -> error_stack::Result<Option<SharedModel<M>>, SendError> {
+		let Some(name) = self.room_name(details) else {
+			return Ok(None);
+		};
+
+		// Leaving the previous model takes its locks, so it has to happen before any are held
+		connection.writer.shared = None;
+
+		// The member is added before the rooms are unlocked, so that the room can't be removed in between
+		let (room, messages) = {
+			let mut rooms = self.rooms.lock().unwrap();
+			let room = self.get_locked(&mut rooms, name);
+			let messages = room.add_member(connection)?;
+
+			(room, messages)
+		};
+
+		send_messages(connection, messages).await?;
+
+		Ok(Some(room))
+	}
+}
+
+async fn send_messages<M, E>(
+	connection: &mut Connection<M, E>,
+	messages: Vec<SocketMessage>,
+) -> error_stack::Result<(), SendError> {
+	for message in messages {
+		connection.writer.send_text(message.to_string()).await?;
+	}
+
+	Ok(())
+}
+
+fn remove_when_empty<M: 'static>(rooms: Weak<Mutex<HashMap<String, SharedModel<M>>>>, name: String) -> OnEmpty {
+	Arc::new(move |state| {
+		let Some(rooms) = rooms.upgrade() else {
+			return;
+		};
+
+		let mut rooms = rooms.lock().unwrap();
+
+		// The room may have been replaced, or joined again since its last member left
+		let empty = rooms.get(&name).is_some_and(|room| {
+			Arc::ptr_eq(&room.state, state) && room.state.lock().unwrap().members.is_empty()
+		});
+
+		if empty {
+			rooms.remove(&name);
+		}
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		tests::{connect_to, next_text},
+		Event, SocketServer,
+	};
+	use futures::SinkExt;
+	use pretty_assertions::assert_eq;
+	use serde_json::json;
+	use tungstenite::Message;
+
+	#[tokio::test]
+	async fn fans_updates_out_to_every_member() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let model = SharedModel::new();
+
+		let (mut first, mut first_client) = connect_to(&mut server, "/").await;
+		let (mut second, mut second_client) = connect_to(&mut server, "/").await;
+
+		model.update(&json!({ "count": 0 })).unwrap();
+
+		model.join(&mut first).await.unwrap();
+		model.join(&mut second).await.unwrap();
+		assert_eq!(model.member_count(), 2);
+
+		assert_eq!(next_text(&mut first_client).await, "model(1) {\"count\":0}");
+		assert_eq!(next_text(&mut second_client).await, "model(1) {\"count\":0}");
+
+		let pin = Uuid::from_u128(7);
+		first_client.send(Message::Text(format!("event({pin}) 1"))).await.unwrap();
+		first.next_event().await.unwrap();
+		first.next_event().await.unwrap();
+		first.send(&json!({ "count": 1 })).await.unwrap();
+
+		assert_eq!(next_text(&mut first_client).await, format!("model(2:{pin}) {{\"count\":1}}"));
+
+		second.next_event().await.unwrap();
+		let second_events = tokio::spawn(async move { second.next_event().await.map(|_| ()) });
+		assert_eq!(next_text(&mut second_client).await, "model(2) {\"count\":1}");
+
+		drop(first);
+		second_events.abort();
+		let _ = second_events.await;
+		assert_eq!(model.member_count(), 0);
+	}
+
+	#[tokio::test]
+	async fn members_catch_up_from_the_shared_version() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let model = SharedModel::new();
+
+		let mut document = json!({ "items": (0..10).map(|n| format!("item {n}")).collect::<Vec<_>>() });
+		model.update(&document).unwrap();
+		document["items"][0] = json!("changed");
+		model.update(&document).unwrap();
+		assert_eq!(model.version(), 2);
+
+		let (mut connection, mut client) = connect_to(&mut server, "/").await;
+		model.join(&mut connection).await.unwrap();
+		assert!(next_text(&mut client).await.starts_with("model(2) "));
+
+		client.send(Message::Text("sync(1)".to_owned())).await.unwrap();
+		client.send(Message::Text("event() null".to_owned())).await.unwrap();
+		connection.next_event().await.unwrap();
+		connection.next_event().await.unwrap();
+
+		assert_eq!(next_text(&mut client).await, "patch(2) [{\"op\":\"replace\",\"path\":\"/items/0\",\"value\":\"changed\"}]");
+	}
+
+	#[tokio::test]
+	async fn rooms_group_connections_by_query_param() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let rooms = Rooms::by_query_param("doc");
+
+		let mut joined = Vec::new();
+
+		for path in ["/?doc=a", "/?doc=b", "/?doc=a", "/"] {
+			let (mut connection, client) = connect_to(&mut server, path).await;
+
+			let details = match connection.next_event().await {
+				Some(Event::Connect(details)) => details,
+				event => panic!("Expected a connect event, got {event:?}"),
+			};
+
+			let room = rooms.join(&mut connection, &details).await.unwrap();
+			joined.push((connection, client, room.is_some()));
+		}
+
+		assert_eq!(joined.iter().map(|(_, _, joined)| *joined).collect::<Vec<_>>(), vec![true, true, true, false]);
+		assert_eq!(rooms.find("a").unwrap().member_count(), 2);
+		assert_eq!(rooms.find("b").unwrap().member_count(), 1);
+		assert!(rooms.find("c").is_none());
+
+		rooms.get("b").update(&json!("for b")).unwrap();
+		rooms.get("a").update(&json!("for a")).unwrap();
+
+		let mut received = Vec::new();
+
+		// Queued updates are sent before the close is processed
+		for (connection, client, _) in &mut joined[..3] {
+			connection.handle().close("Done");
+			connection.next_event().await;
+			received.push(next_text(client).await);
+		}
+
+		assert_eq!(received, vec!["model(1) \"for a\"", "model(1) \"for b\"", "model(1) \"for a\""]);
+	}
+
+	#[tokio::test]
+	async fn removes_rooms_once_their_last_member_leaves() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let rooms = Rooms::by_path();
+
+		let mut joined = Vec::new();
+
+		for _ in 0..2 {
+			let (mut connection, client) = connect_to(&mut server, "/doc").await;
+
+			let details = match connection.next_event().await {
+				Some(Event::Connect(details)) => details,
+				event => panic!("Expected a connect event, got {event:?}"),
+			};
+
+			rooms.join(&mut connection, &details).await.unwrap();
+			joined.push((connection, client));
+		}
+
+		joined.pop();
+		assert_eq!(rooms.find("/doc").unwrap().member_count(), 1);
+
+		joined.pop();
+		assert!(rooms.find("/doc").is_none());
+	}
+
+	#[tokio::test]
+	async fn keeps_one_model_per_room_when_its_last_member_leaves_before_a_join() {
+		let mut server: SocketServer = SocketServer::new(0).await.unwrap();
+		let rooms = Rooms::by_path();
+
+		let mut joined = Vec::new();
+
+		for _ in 0..3 {
+			let (mut connection, client) = connect_to(&mut server, "/doc").await;
+
+			let details = match connection.next_event().await {
+				Some(Event::Connect(details)) => details,
+				event => panic!("Expected a connect event, got {event:?}"),
+			};
+
+			joined.push((connection, client, details));
+		}
+
+		let (first, _, details) = &mut joined[0];
+		rooms.join(first, details).await.unwrap();
+
+		// The last member leaves between looking the room up and joining it
+		let stale = rooms.get("/doc");
+		joined.remove(0);
+		assert!(rooms.find("/doc").is_none());
+
+		for (connection, _, details) in &mut joined {
+			rooms.join(connection, details).await.unwrap();
+		}
+
+		// Joining again replaces the connection's membership instead of adding another
+		let (second, _, details) = &mut joined[0];
+		rooms.join(second, details).await.unwrap();
+
+		let room = rooms.find("/doc").unwrap();
+		assert_eq!((room.member_count(), stale.member_count()), (2, 0));
+
+		room.update(&json!("shared")).unwrap();
+
+		for (connection, client, _) in &mut joined {
+			connection.handle().close("Done");
+			connection.next_event().await;
+			assert_eq!(next_text(client).await, "model(1) \"shared\"");
+		}
+	}
+}
@@ -1,4 +1,6 @@
-use serde_json::{from_str, to_string, Value};
+use error_stack::{Report, ResultExt};
+use serde::{Deserialize, Serialize};
+use serde_json::{error::Category, from_str, to_string, value::RawValue, Value};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -8,6 +10,18 @@ pub enum SocketMessageError {
 
 	#[error("Context didn't finish because a closing parenthesis ')' was not found")]
 	ExpectedClosingContextParen,
+
+	#[error("Prefixes can't contain an opening parenthesis '(', which would start the context")]
+	ParenInPrefix,
+
+	#[error("The body isn't valid JSON, at line {line} column {column} of the body")]
+	InvalidBody { line: usize, column: usize },
+
+	#[error("The body doesn't match the expected type: {0}")]
+	MismatchedBody(String),
+
+	#[error("Failed to serialize the body")]
+	SerializeBody,
 }
 
 type Result<T> = error_stack::Result<T, SocketMessageError>;
@@ -16,64 +30,39 @@ type Result<T> = error_stack::Result<T, SocketMessageError>;
 pub struct SocketMessage {
 	text: String,
 	prefix_end: usize,
-	context_range: Option<(usize, usize)>,
+	/// Unescaped, and `None` when empty
+	context: Option<String>,
 	body_start: Option<usize>,
 }
 
 impl SocketMessage {
+	/// Parse `prefix(context) body`. All offsets are byte indices into the text, and every delimiter is ASCII, so they
+	/// always fall on char boundaries. Within the context, a backslash escapes the character after it, so `\)` and `\\`
+	/// stand for `)` and `\`
 	pub fn parse<S: Into<String>>(text: S) -> Result<SocketMessage> {
 		let text: String = text.into();
-		let mut prefix_end = None;
-		let mut context_start = None;
-		let mut context_end = None;
-		let mut body_start = None;
-
-		let mut index = 0;
-
-		for character in text.chars() {
-			if prefix_end.is_none() {
-				if character == '(' {
-					prefix_end = Some(index);
-				}
-			} else if context_start.is_none() {
-				context_start = Some(index);
-
-				if character == ')' {
-					context_end = Some(index);
-				}
-			} else if context_end.is_none() {
-				if character == ')' {
-					context_end = Some(index);
-				}
-			} else if body_start.is_none() {
-				if !character.is_whitespace() {
-					body_start = Some(index);
-					break;
-				}
-			}
-
-			index += 1;
-		}
-
-		let prefix_end = prefix_end.unwrap_or(text.len());
+		let prefix_end = text.find('(').unwrap_or(text.len());
 
 		if prefix_end == 0 {
 			Err(SocketMessageError::EmptyPrefixNotAllowed)?
 		}
 
-		let context_range = match context_start {
-			Some(context_start) => {
-				let context_end = context_end.ok_or(SocketMessageError::ExpectedClosingContextParen)?;
+		let mut context = None;
+		let mut body_start = None;
+
+		if prefix_end < text.len() {
+			let (unescaped, context_end) = unescape_context(&text, prefix_end + 1)?;
 
-				Some((context_start, context_end))
-			}
-			_ => None,
-		};
+			context = Some(unescaped).filter(|context| !context.is_empty());
+			body_start = text[context_end + 1..]
+				.find(|character: char| !character.is_whitespace())
+				.map(|index| context_end + 1 + index);
+		}
 
 		Ok(SocketMessage {
 			text,
 			prefix_end,
-			context_range,
+			context,
 			body_start,
 		})
 	}
@@ -83,26 +72,34 @@ impl SocketMessage {
 	}
 
 	pub fn get_context(&self) -> Option<&str> {
-		match self.context_range {
-			Some(range) => {
-				if range.0 == range.1 {
-					None
-				} else {
-					Some(&self.text[range.0..range.1])
-				}
-			}
-			None => None,
-		}
+		self.context.as_deref()
+	}
+
+	/// The JSON body, or `None` when the message has none
+	pub fn get_body(&self) -> Result<Option<Value>> {
+		self.body_as()
+	}
+
+	/// The body deserialized straight into `T`, without building a `Value` first. Strings are borrowed from the message
+	/// where `T` allows it
+	pub fn body_as<'a, T: Deserialize<'a>>(&'a self) -> Result<Option<T>> {
+		let Some(index) = self.body_start else {
+			return Ok(None);
+		};
+
+		from_str(&self.text[index..]).map(Some).map_err(body_error)
 	}
 
-	pub fn get_body(&self) -> Option<Value> {
-		self.body_start.map(|index| from_str(&self.text[index..self.text.len()]).ok()).flatten()
+	/// The body as it was sent, once it has been checked to be valid JSON
+	pub fn get_body_raw(&self) -> Result<Option<&RawValue>> {
+		self.body_as()
 	}
 
 	pub fn get_str(&self) -> &str {
 		&self.text
 	}
 
+	#[allow(clippy::inherent_to_string)]
 	pub fn to_string(self) -> String {
 		self.text
 	}
@@ -111,7 +108,7 @@ impl SocketMessage {
 pub struct SocketMessageBuilder<'a> {
 	prefix: String,
 	context: Option<String>,
-	body: Option<&'a Value>,
+	body: Option<&'a dyn erased_serde::Serialize>,
 }
 
 impl<'a> SocketMessageBuilder<'a> {
@@ -129,45 +126,51 @@ impl<'a> SocketMessageBuilder<'a> {
 		self
 	}
 
-	pub fn body(mut self, body: &'a Value) -> SocketMessageBuilder<'a> {
+	/// Set a body which is serialized to JSON once the message is built
+	pub fn body<T: Serialize>(mut self, body: &'a T) -> SocketMessageBuilder<'a> {
 		self.body = Some(body);
 
 		self
 	}
 
+	/// Fails for prefixes which `SocketMessage::parse` couldn't read back, and for bodies which can't be serialized.
+	/// Any context can be written, as its parentheses and backslashes are escaped
 	pub fn build(self) -> Result<SocketMessage> {
+		if self.prefix.is_empty() {
+			Err(SocketMessageError::EmptyPrefixNotAllowed)?
+		}
+
+		if self.prefix.contains('(') {
+			Err(SocketMessageError::ParenInPrefix)?
+		}
+
 		let mut text = self.prefix;
 		let prefix_end = text.len();
-		let mut context_range = None;
+		let context = self.context.filter(|context| !context.is_empty());
 		let mut body_start = None;
 
-		match self.context {
-			Some(context) => {
-				text.push_str("(");
-				let context_start = text.len();
-
-				text.push_str(&context);
-				context_range = Some((context_start, text.len()));
+		// Without parentheses, the body would be read as part of the prefix
+		if context.is_some() || self.body.is_some() {
+			text.push('(');
 
-				text.push_str(")");
+			if let Some(context) = &context {
+				escape_context(context, &mut text);
 			}
-			None => (),
-		};
 
-		match self.body {
-			Some(body) => {
-				text.push_str(" ");
-				body_start = Some(text.len());
+			text.push(')');
+		}
 
-				text.push_str(&to_string(&body).unwrap());
-			}
-			None => (),
-		};
+		if let Some(body) = self.body {
+			text.push(' ');
+			body_start = Some(text.len());
+
+			text.push_str(&to_string(body).change_context(SocketMessageError::SerializeBody)?);
+		}
 
 		Ok(SocketMessage {
 			text,
 			prefix_end,
-			context_range,
+			context,
 			body_start,
 		})
 	}
@@ -177,11 +180,56 @@ impl<'a> SocketMessageBuilder<'a> {
 	}
 }
 
+fn body_error(error: serde_json::Error) -> Report<SocketMessageError> {
+	let context = match error.classify() {
+		Category::Data => SocketMessageError::MismatchedBody(error.to_string()),
+		_ => SocketMessageError::InvalidBody {
+			line: error.line(),
+			column: error.column(),
+		},
+	};
+
+	Report::new(error).change_context(context)
+}
+
+fn escape_context(context: &str, text: &mut String) {
+	for character in context.chars() {
+		if matches!(character, ')' | '\\') {
+			text.push('\\');
+		}
+
+		text.push(character);
+	}
+}
+
+/// Read the context which starts at `start`, returning it unescaped along with the index of its closing parenthesis
+fn unescape_context(text: &str, start: usize) -> Result<(String, usize)> {
+	let mut context = String::new();
+	let mut escaped = false;
+
+	for (index, character) in text[start..].char_indices() {
+		if escaped {
+			context.push(character);
+			escaped = false;
+		} else if character == '\\' {
+			escaped = true;
+		} else if character == ')' {
+			return Ok((context, start + index));
+		} else {
+			context.push(character);
+		}
+	}
+
+	Err(SocketMessageError::ExpectedClosingContextParen)?
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 	use pretty_assertions::assert_eq;
+	use proptest::prelude::*;
 	use serde_json::json;
+	use std::collections::HashMap;
 
 	#[test]
 	fn parse_basic_message() {
@@ -189,7 +237,7 @@ mod tests {
 
 		assert_eq!(message.get_prefix(), "some_prefix");
 		assert_eq!(message.get_context(), Some("context_here"));
-		assert_eq!(message.get_body(), Some(json!({ "body": "here" })))
+		assert_eq!(message.get_body().unwrap(), Some(json!({ "body": "here" })))
 	}
 
 	#[test]
@@ -197,34 +245,152 @@ mod tests {
 		let message = SocketMessage::parse("prefix").unwrap();
 		assert_eq!(message.get_prefix(), "prefix");
 		assert_eq!(message.get_context(), None);
-		assert_eq!(message.get_body(), None);
+		assert_eq!(message.get_body().unwrap(), None);
 
 		let message = SocketMessage::parse("  prefix  ").unwrap();
 		assert_eq!(message.get_prefix(), "  prefix  ");
 		assert_eq!(message.get_context(), None);
-		assert_eq!(message.get_body(), None);
+		assert_eq!(message.get_body().unwrap(), None);
 
 		let message = SocketMessage::parse("prefix  (con text)   ").unwrap();
 		assert_eq!(message.get_prefix(), "prefix  ");
 		assert_eq!(message.get_context(), Some("con text"));
-		assert_eq!(message.get_body(), None);
+		assert_eq!(message.get_body().unwrap(), None);
 
 		let message = SocketMessage::parse("prefix  (con text)   [ \"item 1\" ,\"item2\"]  ").unwrap();
 		assert_eq!(message.get_prefix(), "prefix  ");
 		assert_eq!(message.get_context(), Some("con text"));
-		assert_eq!(message.get_body(), Some(json!(["item 1", "item2"])));
+		assert_eq!(message.get_body().unwrap(), Some(json!(["item 1", "item2"])));
 
 		let message = SocketMessage::parse("prefix()[]").unwrap();
 		assert_eq!(message.get_prefix(), "prefix");
 		assert_eq!(message.get_context(), None);
-		assert_eq!(message.get_body(), Some(json!([])));
+		assert_eq!(message.get_body().unwrap(), Some(json!([])));
 	}
 
 	#[test]
 	fn parse_incorrect_message() {
 		let _ = SocketMessage::parse("").unwrap_err();
 		let _ = SocketMessage::parse("prefix( context_ no closing").unwrap_err();
+		let _ = SocketMessage::parse("prefix(").unwrap_err();
+
+		let body_error = |text: &str| match SocketMessage::parse(text).unwrap().get_body().unwrap_err().current_context() {
+			SocketMessageError::InvalidBody { line, column } => (*line, *column),
+			error => panic!("Expected an invalid body error, got {error:?}"),
+		};
+
+		assert_eq!(body_error("hello () not_valid_json"), (1, 2));
+		assert_eq!(body_error("hello () [1,\n 2,]"), (2, 4));
+	}
+
+	#[derive(Serialize)]
+	struct Counter {
+		total: u32,
+	}
+
+	#[test]
+	fn build_serializable_bodies() {
+		let text = SocketMessageBuilder::new("model").context("1").body(&Counter { total: 3 }).build_string().unwrap();
+		assert_eq!(text, "model(1) {\"total\":3}");
+
+		let unserializable = HashMap::from([((1, 2), 3)]);
+		let error = SocketMessageBuilder::new("model").body(&unserializable).build().unwrap_err();
+		assert!(matches!(error.current_context(), SocketMessageError::SerializeBody));
+	}
 
-		assert_eq!(SocketMessage::parse("hello () not_valid_json").unwrap().get_body(), None)
+	#[derive(Debug, PartialEq, Deserialize)]
+	struct Event<'a> {
+		name: &'a str,
+		count: u32,
+	}
+
+	#[test]
+	fn deserialize_body_without_a_value() {
+		let message = SocketMessage::parse("event() {\"name\": \"add\", \"count\": 2}").unwrap();
+
+		assert_eq!(message.body_as::<Event>().unwrap(), Some(Event { name: "add", count: 2 }));
+		assert_eq!(message.get_body_raw().unwrap().unwrap().get(), "{\"name\": \"add\", \"count\": 2}");
+
+		let error = message.body_as::<Vec<u32>>().unwrap_err();
+		assert!(matches!(error.current_context(), SocketMessageError::MismatchedBody(_)));
+
+		let message = SocketMessage::parse("event() {\"name\": ").unwrap();
+		let error = message.get_body_raw().unwrap_err();
+		assert!(matches!(error.current_context(), SocketMessageError::InvalidBody { .. }));
+
+		assert!(SocketMessage::parse("event").unwrap().body_as::<Event>().unwrap().is_none());
+	}
+
+	#[test]
+	fn parse_non_ascii_message() {
+		let message = SocketMessage::parse("événement(🎉 pin) {\"clé\": \"ü\"}").unwrap();
+
+		assert_eq!(message.get_prefix(), "événement");
+		assert_eq!(message.get_context(), Some("🎉 pin"));
+		assert_eq!(message.get_body().unwrap(), Some(json!({ "clé": "ü" })));
+	}
+
+	#[test]
+	fn escape_parens_in_context() {
+		let body = json!([1]);
+		let text = SocketMessageBuilder::new("event").context("a) \\b").body(&body).build_string().unwrap();
+		assert_eq!(text, "event(a\\) \\\\b) [1]");
+
+		let message = SocketMessage::parse(text).unwrap();
+		assert_eq!(message.get_context(), Some("a) \\b"));
+		assert_eq!(message.get_body().unwrap(), Some(body));
+
+		let _ = SocketMessage::parse("prefix(escaped\\)").unwrap_err();
+	}
+
+	#[test]
+	fn build_rejects_unreadable_prefixes() {
+		let _ = SocketMessageBuilder::new("").build().unwrap_err();
+		let _ = SocketMessageBuilder::new("pre(fix").build().unwrap_err();
+
+		let body = json!(null);
+		let text = SocketMessageBuilder::new("prefix").body(&body).build_string().unwrap();
+		assert_eq!(SocketMessage::parse(text).unwrap().get_body().unwrap(), Some(body));
+	}
+
+	proptest! {
+		#[test]
+		fn parse_never_panics(text in any::<String>()) {
+			if let Ok(message) = SocketMessage::parse(text.as_str()) {
+				let _ = (message.get_prefix(), message.get_context(), message.get_body().is_ok());
+			}
+		}
+
+		#[test]
+		fn parse_finds_every_part(prefix in "[^(]+", context in r"[^)\\]*", body in any::<i64>()) {
+			let message = SocketMessage::parse(format!("{prefix}({context}) {body}")).unwrap();
+
+			prop_assert_eq!(message.get_prefix(), prefix.as_str());
+			prop_assert_eq!(message.get_context(), Some(context.as_str()).filter(|context| !context.is_empty()));
+			prop_assert_eq!(message.get_body().unwrap(), Some(json!(body)));
+		}
+
+		#[test]
+		fn build_round_trips(prefix in "[^(]+", context in any::<Option<String>>(), body in any::<Option<String>>()) {
+			let body = body.map(Value::String);
+			let mut builder = SocketMessageBuilder::new(prefix.as_str());
+
+			if let Some(context) = &context {
+				builder = builder.context(context.as_str());
+			}
+
+			if let Some(body) = &body {
+				builder = builder.body(body);
+			}
+
+			let built = builder.build().unwrap();
+			let message = SocketMessage::parse(built.get_str()).unwrap();
+			let context = context.filter(|context| !context.is_empty());
+
+			prop_assert_eq!(built.get_context(), context.as_deref());
+			prop_assert_eq!(message.get_prefix(), prefix.as_str());
+			prop_assert_eq!(message.get_context(), context.as_deref());
+			prop_assert_eq!(message.get_body().unwrap(), body);
+		}
 	}
 }
@@ -1,5 +1,5 @@
 import { assertEquals } from 'asserts'
-import { parseSocketMessage } from './socket_message.ts'
+import { parseSocketMessage, stringifySocketMessage } from './socket_message.ts'
 
 Deno.test('parseSocketMessage', () => {
 	assertEquals(parseSocketMessage('prefix(context) []'), { prefix: 'prefix', context: 'context', body: [] })
@@ -11,3 +11,11 @@ Deno.test('parseSocketMessage', () => {
 	assertEquals(parseSocketMessage('prefix [ "not", "body"]'), { prefix: 'prefix [ "not", "body"]', body: null, context: null })
 	assertEquals(parseSocketMessage('prefix() ["foo"]'), { prefix: 'prefix', context: null, body: ['foo'] })
 })
+
+Deno.test('stringifySocketMessage escapes the context', () => {
+	const message = { prefix: 'event', context: 'a) \\b', body: [1] }
+	const text = stringifySocketMessage(message)
+
+	assertEquals(text, 'event(a\\) \\\\b) [1]')
+	assertEquals(parseSocketMessage(text), message)
+})
@@ -6,17 +6,33 @@ export interface SocketMessage {
 
 const indexWithFallback = (number: number, fallback: number) => number == -1 ? fallback : number
 
+/** Within the context, a backslash escapes the character after it, so `\)` and `\\` stand for `)` and `\` */
+function readContext(text: string, start: number): { context: string; end: number } {
+	let context = ''
+
+	for (let index = start; index < text.length; index++) {
+		const character = text[index]
+
+		if (character === '\\' && index + 1 < text.length) context += text[++index]
+		else if (character === ')') return { context, end: index }
+		else context += character
+	}
+
+	return { context, end: text.length }
+}
+
+const escapeContext = (context: string) => context.replace(/[\\)]/g, (character) => `\\${character}`)
+
 export function parseSocketMessage(text: string): SocketMessage {
 	const firstParen = indexWithFallback(text.indexOf('('), text.length)
-	const secondParen = indexWithFallback(text.indexOf(')', firstParen), text.length)
+	const { context, end } = readContext(text, firstParen + 1)
 	const prefix = text.slice(0, firstParen)
-	const context = text.slice(firstParen + 1, secondParen) || null
-	const bodyString = text.slice(secondParen + 1).trim() || null
+	const bodyString = text.slice(end + 1).trim() || null
 	const body = bodyString ? JSON.parse(bodyString) : null
 
-	return { prefix, context, body }
+	return { prefix, context: context || null, body }
 }
 
 export function stringifySocketMessage(message: SocketMessage): string {
-	return `${message.prefix}(${message.context ?? ''}) ${JSON.stringify(message.body)}`
+	return `${message.prefix}(${escapeContext(message.context ?? '')}) ${JSON.stringify(message.body)}`
 }
@@ -0,0 +1,151 @@
+use crate::SocketServerError;
+use error_stack::{Report, ResultExt};
+use std::{path::PathBuf, sync::Arc};
+use tokio_rustls::{
+	rustls::{
+		crypto::ring::default_provider,
+		pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+		ServerConfig,
+	},
+	TlsAcceptor,
+};
+
+type Result<T> = error_stack::Result<T, SocketServerError>;
+
+/// Where the TLS configuration of a `SocketServerBuilder` comes from
+pub(crate) enum TlsSource {
+	Config(Arc<ServerConfig>),
+	Pem { certificates: PathBuf, key: PathBuf },
+}
+
+impl TlsSource {
+	pub fn acceptor(self) -> Result<TlsAcceptor> {
+		let config = match self {
+			TlsSource::Config(config) => config,
+			TlsSource::Pem { certificates, key } => Arc::new(load_pem(certificates, key)?),
+		};
+
+		Ok(TlsAcceptor::from(config))
+	}
+}
+
+fn load_pem(certificates: PathBuf, key: PathBuf) -> Result<ServerConfig> {
+	let chain = CertificateDer::pem_file_iter(&certificates)
+		.and_then(|certificates| certificates.collect::<std::result::Result<Vec<_>, _>>())
+		.change_context_lazy(|| SocketServerError::TlsCertificates(certificates.clone()))?;
+
+	if chain.is_empty() {
+		Err(Report::new(SocketServerError::TlsCertificates(certificates))
+			.attach_printable("The file doesn't contain any certificates"))?
+	}
+
+	let private_key = PrivateKeyDer::from_pem_file(&key).change_context(SocketServerError::TlsKey(key))?;
+
+	// Use ring explicitly rather than the process-wide default provider, which may not be installed
+	ServerConfig::builder_with_provider(Arc::new(default_provider()))
+		.with_safe_default_protocol_versions()
+		.change_context(SocketServerError::TlsConfig)?
+		.with_no_client_auth()
+		.with_single_cert(chain, private_key)
+		.change_context(SocketServerError::TlsConfig)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{tests::next_text, Event, SocketServer};
+	use pretty_assertions::assert_eq;
+	use rcgen::{generate_simple_self_signed, CertifiedKey};
+	use serde_json::{json, Value};
+	use std::{env::temp_dir, fs::write, time::Duration};
+	use tokio::{io::AsyncReadExt, net::TcpStream, time::timeout};
+	use tokio_rustls::{
+		rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+		TlsConnector,
+	};
+	use tokio_tungstenite::client_async;
+
+	#[tokio::test]
+	async fn serves_wss_with_a_self_signed_certificate() {
+		let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+		let directory = temp_dir().join(format!("socket_server_tls_{}", std::process::id()));
+		std::fs::create_dir_all(&directory).unwrap();
+		write(directory.join("cert.pem"), cert.pem()).unwrap();
+		write(directory.join("key.pem"), key_pair.serialize_pem()).unwrap();
+
+		let mut server: SocketServer = SocketServer::builder()
+			.tls_pem(directory.join("cert.pem"), directory.join("key.pem"))
+			.build()
+			.await
+			.unwrap();
+
+		let mut roots = RootCertStore::empty();
+		roots.add(cert.der().clone()).unwrap();
+
+		let config = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+			.with_safe_default_protocol_versions()
+			.unwrap()
+			.with_root_certificates(roots)
+			.with_no_client_auth();
+
+		let stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+		let stream = TlsConnector::from(Arc::new(config))
+			.connect(ServerName::try_from("localhost").unwrap(), stream)
+			.await
+			.unwrap();
+
+		let (mut client, _) = client_async("wss://localhost/secure", stream).await.unwrap();
+		let mut connection = server.accept_connection().await.unwrap();
+
+		match connection.next_event().await {
+			Some(Event::Connect(details)) => assert_eq!(details.path, "/secure"),
+			event => panic!("Expected a connect event, got {event:?}"),
+		}
+
+		connection.send(&json!([1])).await.unwrap();
+		assert_eq!(next_text(&mut client).await, "model(1) [1]");
+
+		std::fs::remove_dir_all(directory).unwrap();
+	}
+
+	#[tokio::test]
+	async fn drops_connections_which_never_finish_the_handshake() {
+		let CertifiedKey { cert, key_pair } = generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+		let key = PrivateKeyDer::try_from(key_pair.serialize_der()).unwrap();
+
+		let config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+			.with_safe_default_protocol_versions()
+			.unwrap()
+			.with_no_client_auth()
+			.with_single_cert(vec![cert.der().clone()], key)
+			.unwrap();
+
+		let server: SocketServer = SocketServer::builder()
+			.tls_config(Arc::new(config))
+			.tls_handshake_timeout(Duration::from_millis(50))
+			.build()
+			.await
+			.unwrap();
+
+		// Never sends a ClientHello, so only the timeout ends the handshake
+		let mut stream = TcpStream::connect(server.local_addr().unwrap()).await.unwrap();
+		let read = timeout(Duration::from_secs(5), stream.read(&mut [0; 16])).await;
+
+		assert!(matches!(read, Ok(Ok(0))), "Expected the server to close the stream, got {read:?}");
+	}
+
+	#[tokio::test]
+	async fn reports_unreadable_pem_files() {
+		let missing = temp_dir().join("socket_server_missing_cert.pem");
+
+		let error = SocketServer::<Value, Value>::builder()
+			.tls_pem(&missing, &missing)
+			.build()
+			.await
+			.err()
+			.unwrap();
+
+		assert!(matches!(error.current_context(), SocketServerError::TlsCertificates(path) if *path == missing));
+	}
+}
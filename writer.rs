@@ -0,0 +1,131 @@
+use crate::{model_state::ModelState, shared_model::Membership, CloseReason, SendError, Signal, SocketMessage};
+use error_stack::ResultExt;
+use futures::{stream::SplitSink, SinkExt};
+use hyper::upgrade::Upgraded;
+use hyper_util::rt::TokioIo;
+use serde_json::Value;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
+use tokio_tungstenite::WebSocketStream;
+use tungstenite::Message;
+use uuid::Uuid;
+
+pub(crate) type Socket = WebSocketStream<TokioIo<Upgraded>>;
+
+/// The sending half of a connection's socket, along with the model state which decides what to send
+pub(crate) struct Writer {
+	sink: SplitSink<Socket, Message>,
+	pub model: ModelState,
+	pub shared: Option<Membership>,
+	pub client_pin: Option<Uuid>,
+	closed: Arc<Closed>,
+}
+
+/// How the server closed a connection, shared by its writing and reading halves
+#[derive(Default)]
+pub(crate) struct Closed {
+	reason: OnceLock<CloseReason>,
+	/// Notified once the close frame has been written
+	written: Notify,
+}
+
+impl Closed {
+	/// The reason the server closed the connection with, once it has
+	pub fn reason(&self) -> Option<&CloseReason> {
+		self.reason.get()
+	}
+
+	/// Wait until the close frame has been written. Cancel-safe
+	pub async fn written(&self) {
+		self.written.notified().await
+	}
+}
+
+impl Writer {
+	pub fn new(sink: SplitSink<Socket, Message>) -> Writer {
+		Writer {
+			sink,
+			model: ModelState::default(),
+			shared: None,
+			client_pin: None,
+			closed: Arc::default(),
+		}
+	}
+
+	pub async fn send_text(&mut self, text: String) -> error_stack::Result<(), SendError> {
+		self.sink.send(Message::Text(text)).await.change_context(SendError::Closed)
+	}
+
+	pub async fn send_ping(&mut self, payload: Vec<u8>) -> error_stack::Result<(), SendError> {
+		self.sink.send(Message::Ping(payload)).await.change_context(SendError::Closed)
+	}
+
+	pub async fn send_value(&mut self, state: Value) -> error_stack::Result<(), SendError> {
+		let message = match &self.shared {
+			Some(membership) => membership.update(state, self.client_pin)?,
+			None => {
+				self.model.update(state);
+				self.model.update_message(self.client_pin)?.to_string()
+			}
+		};
+
+		self.send_text(message).await
+	}
+
+	pub async fn sync(&mut self, since: Option<u64>) -> error_stack::Result<(), SendError> {
+		let messages = match &self.shared {
+			Some(membership) => membership.sync_messages(since)?,
+			None => {
+				let messages = self.model.sync_messages(since)?;
+
+				messages.into_iter().map(SocketMessage::to_string).collect()
+			}
+		};
+
+		for message in messages {
+			self.send_text(message).await?;
+		}
+
+		Ok(())
+	}
+
+	pub fn closed(&self) -> Arc<Closed> {
+		self.closed.clone()
+	}
+
+	pub async fn close(&mut self, reason: CloseReason) {
+		// Recorded first, so that the reading half knows the client's close frame is a reply
+		let _ = self.closed.reason.set(reason.clone());
+
+		let _ = self.sink.send(Message::Close(Some(reason.into_frame()))).await;
+
+		// The reading half may be waiting on a client which never replies
+		self.closed.written.notify_one();
+	}
+
+	/// Act on a signal. Returns false once the signal has closed the connection
+	pub async fn handle_signal(&mut self, signal: Signal) -> bool {
+		match signal {
+			Signal::Model(model) => {
+				let _ = self.send_value(model).await;
+			}
+			Signal::Message(text) => {
+				let _ = self.send_text(text).await;
+			}
+			Signal::Sync(since) => {
+				let _ = self.sync(since).await;
+			}
+			Signal::Pin(pin) => self.client_pin = pin,
+			Signal::Ping(payload) => {
+				let _ = self.send_ping(payload).await;
+			}
+			Signal::Close(reason) => {
+				self.close(reason).await;
+
+				return false;
+			}
+		}
+
+		true
+	}
+}